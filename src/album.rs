@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use teloxide::types::Message;
+use tokio::time::Instant;
+
+/// How long to wait for the next item of an album before taking it as
+/// complete.
+const ALBUM_DELAY: Duration = Duration::from_secs(1);
+
+/// Items of an album that is still arriving, and when the last one came.
+type Group = (Vec<Message>, Instant);
+
+/// Items of albums that are still arriving, keyed by `media_group_id`.
+#[derive(Clone, Default)]
+pub struct Albums {
+    groups: Arc<Mutex<HashMap<String, Group>>>,
+}
+
+impl Albums {
+    fn groups(&self) -> std::sync::MutexGuard<'_, HashMap<String, Group>> {
+        self.groups.lock().expect("albums are not poisoned")
+    }
+
+    /// Adds an item to its album and returns whether it is the first one.
+    pub fn push(&self, group: &str, m: Message) -> bool {
+        let mut groups = self.groups();
+        let (items, last) = groups
+            .entry(group.to_owned())
+            .or_insert_with(|| (Vec::new(), Instant::now()));
+        items.push(m);
+        *last = Instant::now();
+        items.len() == 1
+    }
+
+    /// Waits until no item of the album has arrived for [`ALBUM_DELAY`], so
+    /// that slow uploads still make one album, and takes all of its items.
+    pub async fn collect(&self, group: &str) -> Vec<Message> {
+        loop {
+            let deadline = match self.groups().get(group) {
+                Some((_, last)) => *last + ALBUM_DELAY,
+                None => return Vec::new(),
+            };
+            tokio::time::sleep_until(deadline).await;
+            let mut groups = self.groups();
+            let quiet = match groups.get(group) {
+                Some((_, last)) => *last + ALBUM_DELAY <= Instant::now(),
+                None => true,
+            };
+            if quiet {
+                let mut items = groups
+                    .remove(group)
+                    .map(|(items, _)| items)
+                    .unwrap_or_default();
+                items.sort_by_key(|m| m.id);
+                return items;
+            }
+        }
+    }
+}
@@ -1,155 +1,924 @@
-use crate::{errors::*, types::*};
+use crate::{
+    effects::{add_outline, apply_key, corner_color, key_filter, outline_width, render_text},
+    errors::*,
+    geometry::fit,
+    media_info::MediaInfo,
+    queue::Queue,
+    source::Source,
+    types::*,
+};
+use color_quant::NeuQuant;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use image::{
-    imageops::FilterType, io::Reader as ImageReader, GenericImage, ImageOutputFormat, Rgba,
-    RgbaImage,
+    codecs::{
+        png::{CompressionType, FilterType as PngFilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    imageops::{self, FilterType},
+    io::Reader as ImageReader,
+    ColorType, GenericImage, ImageEncoder, ImageFormat, Rgba, RgbaImage,
 };
+use serde_json::Value;
 use std::{
-    io::Cursor,
+    collections::HashSet,
+    env,
+    io::{Cursor, ErrorKind, Read, Write},
     path::Path,
     process::{Output, Stdio},
-    str::FromStr,
+    time::Duration,
 };
 use teloxide::{
     adaptors::AutoSend, net::Download, prelude::Requester, types::File as TgFile, types::InputFile,
     Bot,
 };
-use tempfile::NamedTempFile;
-use tokio::{fs::File, process::Command};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
 use ubyte::ToByteUnit;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
 
-fn convert_image(path: &Path, layout: Option<LayoutProp>) -> anyhow::Result<(Vec<u8>, LayoutProp)> {
-    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+/// Telegram rejects static stickers larger than this many KiB.
+const MAX_IMAGE_SIZE: u64 = 512;
+/// Telegram rejects video stickers larger than this many KiB.
+const MAX_VIDEO_SIZE: u64 = 256;
+/// Telegram rejects video custom emoji larger than this many KiB.
+const MAX_EMOJI_VIDEO_SIZE: u64 = 64;
+/// Telegram rejects animated stickers larger than this many KiB once gzipped.
+const MAX_LOTTIE_SIZE: u64 = 64;
+/// Highest frame rate Telegram accepts for animated stickers.
+const MAX_FRAME_RATE: f32 = 60.0;
+/// Highest frame rate Telegram accepts for video stickers.
+const MAX_VIDEO_FRAME_RATE: f32 = 30.0;
+/// Most files converted from a single archive.
+const MAX_ARCHIVE_ENTRIES: usize = 50;
+/// Qualities tried in turn until the encoded video fits in [`MAX_VIDEO_SIZE`].
+const CRF_LADDER: [u8; 5] = [35, 42, 48, 55, 63];
+/// Longest an ffprobe run may take, unless `FFPROBE_TIMEOUT` says otherwise.
+const FFPROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a single ffmpeg run may take, unless `FFMPEG_TIMEOUT` says
+/// otherwise.
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bump whenever a change to the conversion changes its output, so that
+/// cached results of the older code are not sent again.
+const ENCODER_VERSION: u32 = 5;
+
+/// A converted sticker file along with the props it was converted with.
+pub struct Sticker {
+    pub file: InputFile,
+    pub size: u64,
+    pub layout: LayoutProp,
+    pub playback: Option<PlaybackProp>,
+    /// Whether the media itself, padding aside, has see-through parts. Not
+    /// known for stickers sent before and Lottie animations.
+    pub translucent: Option<bool>,
+}
+
+fn encode_png(img: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    PngEncoder::new_with_quality(&mut data, CompressionType::Best, PngFilterType::Adaptive)
+        .write_image(img, img.width(), img.height(), ColorType::Rgba8)?;
+    Ok(data)
+}
+
+fn encode_webp(img: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    WebPEncoder::new_lossless(&mut data).write_image(
+        img,
+        img.width(),
+        img.height(),
+        ColorType::Rgba8,
+    )?;
+    Ok(data)
+}
+
+/// Quantises the image to a 256-colour palette, keeping per-entry alpha, and
+/// writes it as an indexed PNG.
+fn encode_indexed_png(img: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let nq = NeuQuant::new(10, 256, img.as_raw());
+    let palette = nq.color_map_rgba();
+    let indices: Vec<u8> = img.pixels().map(|p| nq.index_of(&p.0) as u8).collect();
+
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, img.width(), img.height());
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Best);
+        encoder.set_palette(
+            palette
+                .chunks(4)
+                .flat_map(|c| &c[..3])
+                .copied()
+                .collect::<Vec<_>>(),
+        );
+        encoder.set_trns(palette.chunks(4).map(|c| c[3]).collect::<Vec<_>>());
+        encoder.write_header()?.write_image_data(&indices)?;
+    }
+    Ok(data)
+}
+
+/// Encodes in the preferred format, falling back to an indexed PNG when the
+/// result does not fit in [`MAX_IMAGE_SIZE`].
+fn encode_image(img: &RgbaImage, format: OutputFormat) -> anyhow::Result<(Vec<u8>, OutputFormat)> {
+    let data = match format {
+        OutputFormat::Png => encode_png(img)?,
+        OutputFormat::WebP => encode_webp(img)?,
+    };
+    log::debug!("output length as {}: {}.", format, data.len());
+    if data.len().bytes() <= MAX_IMAGE_SIZE.kibibytes() {
+        return Ok((data, format));
+    }
+
+    let data = encode_indexed_png(img)?;
+    log::debug!("output length as indexed png: {}.", data.len());
+    anyhow::ensure!(
+        data.len().bytes() <= MAX_IMAGE_SIZE.kibibytes(),
+        ConvertError::OutputSize(data.len() as u64, MAX_IMAGE_SIZE.kibibytes())
+    );
+    Ok((data, OutputFormat::Png))
+}
+
+/// Reads a timeout in seconds from the environment.
+fn timeout(var: &str, default: Duration) -> Duration {
+    env::var(var)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .map(Duration::from_secs_f32)
+        .unwrap_or(default)
+}
+
+/// Kills a process group when dropped, so that a subprocess does not outlive
+/// a conversion that timed out or was cancelled.
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            log::debug!("kill process group {}", pid);
+            // SAFETY: killpg has no memory effects; at worst the group is gone.
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Runs a program in a process group of its own and returns its output. The
+/// whole group is killed if it runs longer than `limit` or the future is
+/// dropped.
+async fn run(program: &str, args: &[&str], limit: Duration) -> anyhow::Result<Vec<u8>> {
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut group = ProcessGroup(child.id());
+
+    let output = tokio::time::timeout(limit, child.wait_with_output()).await;
+    let Output { stdout, status, .. } = match output {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(ConvertError::Timeout(program.to_owned(), limit.as_secs_f32()).into())
+        }
+    };
+    group.0 = None;
+    anyhow::ensure!(
+        status.success(),
+        "{} exited with {:?}",
+        program,
+        status.code()
+    );
+    Ok(stdout)
+}
+
+async fn ffmpeg(args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    run("ffmpeg", args, timeout("FFMPEG_TIMEOUT", FFMPEG_TIMEOUT)).await
+}
+
+async fn ffprobe(args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    run("ffprobe", args, timeout("FFPROBE_TIMEOUT", FFPROBE_TIMEOUT)).await
+}
+
+/// Runs two ffmpegs, passing each raw frame of `frame_len` bytes that the
+/// first one decodes through `draw` into the second one, and returns the
+/// output of the second one. Only a frame at a time is held. Both are killed
+/// the way [`run`] kills a program.
+async fn ffmpeg_pipe(
+    decode_args: &[&str],
+    frame_len: usize,
+    mut draw: impl FnMut(&[u8]) -> RgbaImage,
+    encode_args: &[&str],
+) -> anyhow::Result<Vec<u8>> {
+    let spawn = |args: &[&str], stdin: Stdio| {
+        Command::new("ffmpeg")
+            .args(args)
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+    };
+    let mut decoder = spawn(decode_args, Stdio::null())?;
+    let mut decoder_group = ProcessGroup(decoder.id());
+    let mut encoder = spawn(encode_args, Stdio::piped())?;
+    let mut encoder_group = ProcessGroup(encoder.id());
+    let mut frames = decoder.stdout.take().expect("stdout is piped");
+    let mut input = encoder.stdin.take().expect("stdin is piped");
+    let mut output = encoder.stdout.take().expect("stdout is piped");
+
+    let feed = async move {
+        let mut raw = vec![0; frame_len];
+        loop {
+            match frames.read_exact(&mut raw).await {
+                Ok(_) => input.write_all(draw(&raw).as_raw()).await?,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Closing the input ends the clip for the encoder.
+        drop(input);
+        anyhow::Ok(())
+    };
+    let mut stdout = Vec::new();
+    let work = async {
+        let (fed, read) = tokio::join!(feed, output.read_to_end(&mut stdout));
+        fed?;
+        read?;
+        for status in [decoder.wait().await?, encoder.wait().await?] {
+            anyhow::ensure!(status.success(), "ffmpeg exited with {:?}", status.code());
+        }
+        anyhow::Ok(())
+    };
+    let limit = timeout("FFMPEG_TIMEOUT", FFMPEG_TIMEOUT);
+    match tokio::time::timeout(limit, work).await {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ConvertError::Timeout("ffmpeg".to_owned(), limit.as_secs_f32()).into())
+        }
+    }
+    decoder_group.0 = None;
+    encoder_group.0 = None;
+    Ok(stdout)
+}
+
+/// Whether the file is an animated PNG, of which the image decoder would
+/// only read the first frame.
+fn is_apng(path: &Path) -> bool {
+    std::fs::File::open(path)
+        .ok()
+        .and_then(|f| png::Decoder::new(f).read_info().ok())
+        .is_some_and(|reader| reader.info().animation_control.is_some())
+}
+
+/// Animated PNGs arrive as images, but are converted as videos.
+fn detect_media_type(path: &Path, media_type: MediaType) -> MediaType {
+    match media_type {
+        MediaType::Image if is_apng(path) => MediaType::Video,
+        media_type => media_type,
+    }
+}
+
+fn convert_image(
+    path: &Path,
+    layout: Option<LayoutProp>,
+    effects: &Effects,
+    settings: Settings,
+) -> anyhow::Result<(Vec<u8>, LayoutProp, OutputFormat, bool)> {
+    let mut img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
     let (width, height) = (img.width(), img.height());
+    if let Some(key) = effects.key {
+        let mut rgba = img.into_rgba8();
+        apply_key(&mut rgba, key);
+        img = rgba.into();
+    }
 
-    let layout = layout.unwrap_or((width, height).into());
-    let (b_width, b_height, pad_x) = layout.resize(width, height);
+    let layout = layout.unwrap_or_else(|| settings.layout(width, height));
+    let placement = layout.resize(width, height, effects.crop);
+    let rect = placement.crop;
+    let img = img.crop_imm(rect.x, rect.y, rect.width, rect.height);
+    let (b_width, b_height) = placement.bounds;
+    // The outline grows the media, so it is fitted in a smaller box first.
+    let border = effects
+        .outline
+        .map_or(0, |o| outline_width(o, b_width, b_height));
 
-    let img = img.resize(b_width, b_height, FilterType::CatmullRom);
-    let img = pad_x
-        .and_then(|x| {
+    let (f_width, f_height) = fit(
+        rect.width,
+        rect.height,
+        b_width - 2 * border,
+        b_height - 2 * border,
+    );
+    let img = img
+        .resize_exact(f_width, f_height, FilterType::CatmullRom)
+        .into_rgba8();
+    let mut img = match effects.outline {
+        Some(outline) => add_outline(&img, outline.color.rgb(), border),
+        None => img,
+    };
+    if let Some(text) = &effects.text {
+        let layer = render_text(img.width(), img.height(), text)?;
+        imageops::overlay(&mut img, &layer, 0, 0);
+    }
+    let translucent = img.pixels().any(|p| p[3] < 255);
+    let img = place(img, layout, b_width, b_height);
+
+    let (converted, format) = encode_image(&img, settings.format)?;
+    Ok((converted, layout, format, translucent))
+}
+
+/// Pads the media to fill the box if the layout asks for it.
+fn place(img: RgbaImage, layout: LayoutProp, b_width: u32, b_height: u32) -> RgbaImage {
+    match layout.offset(img.width(), img.height()) {
+        Some((x, y)) => {
             let mut canvas = RgbaImage::from_pixel(b_width, b_height, Rgba([0; 4]));
-            canvas.copy_from(&img, x, 0).map(|_| canvas.into()).ok()
-        })
-        .unwrap_or(img);
+            match canvas.copy_from(&img, x, y) {
+                Ok(()) => canvas,
+                Err(_) => img,
+            }
+        }
+        None => img,
+    }
+}
 
-    let mut converted: Vec<u8> = Vec::new();
-    img.write_to(&mut Cursor::new(&mut converted), ImageOutputFormat::Png)?;
-    Ok((converted, layout))
+pub async fn probe(path: &Path) -> Result<MediaInfo, ConvertError> {
+    #[rustfmt::skip]
+    let args = [
+        "-v", "error", "-print_format", "json", "-show_streams", "-show_format",
+        path.to_str().expect("path of tempfile"),
+    ];
+    let stdout = ffprobe(&args).await.map_err(ConvertError::wrap)?;
+    let info = MediaInfo::from_probe(&stdout)?;
+    log::debug!("video metadata: {:?}", info);
+    Ok(info)
+}
+
+/// Decodes the frame a clip starts from, given the input args of ffmpeg.
+async fn first_frame(input_args: &[&str]) -> anyhow::Result<RgbaImage> {
+    #[rustfmt::skip]
+    let args = [
+        input_args,
+        &["-frames:v", "1", "-an", "-f", "image2pipe", "-c:v", "png", "-"],
+    ]
+    .concat();
+    let stdout = ffmpeg(&args).await?;
+    Ok(image::load_from_memory_with_format(&stdout, ImageFormat::Png)?.into_rgba8())
+}
+
+/// Decodes the frames of a clip fitted in the box of the layout, draws the
+/// outline and text on each and pads them as the layout asks, since ffmpeg
+/// has no filters for those, and encodes them with `output_args` as they
+/// come. The size is that of the clip once `filters` have cropped it.
+#[allow(clippy::too_many_arguments)]
+async fn encode_drawn(
+    input_args: &[&str],
+    filters: &str,
+    fps: f32,
+    duration: &str,
+    layout: LayoutProp,
+    (width, height): (u32, u32),
+    effects: &Effects,
+    output_args: &[&str],
+) -> anyhow::Result<Vec<u8>> {
+    let (b_width, b_height) = layout.resize(width, height, Crop::Fit).bounds;
+    let border = effects
+        .outline
+        .map_or(0, |o| outline_width(o, b_width, b_height));
+    let (f_width, f_height) = fit(width, height, b_width - 2 * border, b_height - 2 * border);
+    let vf = format!("{}fps={},scale={}:{}", filters, fps, f_width, f_height);
+    log::debug!("ffmpeg vf: {}", vf);
+    #[rustfmt::skip]
+    let decode_args = [
+        input_args,
+        &["-t", duration, "-an", "-vf", &vf, "-f", "rawvideo", "-pix_fmt", "rgba", "-"],
+    ]
+    .concat();
+
+    // The text is the same on every frame, so it is rendered once.
+    let (o_width, o_height) = (f_width + 2 * border, f_height + 2 * border);
+    let layer = effects
+        .text
+        .as_ref()
+        .map(|text| render_text(o_width, o_height, text))
+        .transpose()?;
+
+    let size = match layout.offset(o_width, o_height) {
+        Some(_) => format!("{}x{}", b_width, b_height),
+        None => format!("{}x{}", o_width, o_height),
+    };
+    let fps = fps.to_string();
+    #[rustfmt::skip]
+    let encode_args = [
+        &["-f", "rawvideo", "-pix_fmt", "rgba", "-s", &size, "-r", &fps, "-i", "-"],
+        output_args,
+    ]
+    .concat();
+
+    let draw = |raw: &[u8]| {
+        let frame = RgbaImage::from_raw(f_width, f_height, raw.to_vec())
+            .expect("frames are of the size asked for");
+        let mut frame = match effects.outline {
+            Some(outline) => add_outline(&frame, outline.color.rgb(), border),
+            None => frame,
+        };
+        if let Some(layer) = &layer {
+            imageops::overlay(&mut frame, layer, 0, 0);
+        }
+        place(frame, layout, b_width, b_height)
+    };
+    let frame_len = (f_width * f_height * 4) as usize;
+    ffmpeg_pipe(&decode_args, frame_len, draw, &encode_args).await
 }
 
 async fn convert_video(
     path: &Path,
+    info: &MediaInfo,
     layout: Option<LayoutProp>,
     playback: Option<PlaybackProp>,
+    effects: &Effects,
+    settings: Settings,
 ) -> anyhow::Result<(Vec<u8>, LayoutProp, PlaybackProp)> {
-    log::debug!("convert a video with {:?}, {:?}...", layout, playback);
-    #[rustfmt::skip]
-    let args = [
-        "-select_streams", "v", "-show_entries", "stream=width,height:format=duration",
-        "-of", "default=nokey=1:noprint_wrappers=1",
-        path.to_str().expect("path of tempfile"),
-    ];
-    let Output { stdout, status, .. } = Command::new("ffprobe")
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .output()
-        .await?;
-    anyhow::ensure!(status.success(), "ffprobe exited with {:?}", status.code());
-    let probe: Vec<_> = std::str::from_utf8(&stdout)?.split('\n').collect();
-
-    fn parse<T: FromStr>(s: Option<&&str>, name: &str) -> Result<T, ConvertError> {
-        let s = *s.unwrap_or(&"");
-        s.parse()
-            .map_err(|_| ConvertError::Format(name.to_owned(), s.to_owned()))
-    }
-    let width: u32 = parse(probe.get(0), "width")?;
-    let height: u32 = parse(probe.get(1), "height")?;
-    let duration: f32 = parse(probe.get(2), "duration")?;
-    log::debug!("video metadata: {}*{}, {:.3}s", width, height, duration);
-
-    let layout = layout.unwrap_or((width, height).into());
-    let playback = playback.unwrap_or(PlaybackProp { speed_up: false });
+    log::debug!(
+        "convert a {} video in {} of {:?} frames with {:?}, {:?}, {:?}...",
+        info.codec,
+        info.pixel_format,
+        info.frame_count,
+        layout,
+        playback,
+        effects
+    );
+    let (width, height) = info.display_size();
+    let duration = info.duration;
+
+    let layout = layout.unwrap_or_else(|| settings.layout(width, height));
+    let playback = playback.unwrap_or_else(|| settings.playback(duration));
     anyhow::ensure!(
-        duration <= 3.0 || playback.speed_up,
+        duration <= MAX_DURATION || playback.speed_up || playback.trim.is_some(),
         ConvertError::Duration(duration)
     );
 
-    let (b_width, b_height, pad_x) = layout.resize(width, height);
-    let scale = format!(
-        ",scale={}:{}:force_original_aspect_ratio=decrease",
-        b_width, b_height
-    );
-    let pad = pad_x
-        .map(|x| format!(",pad={}:{}:{}:0:black@0", b_width, b_height, x))
+    let placement = layout.resize(width, height, effects.crop);
+    let rect = placement.crop;
+    let crop = if (rect.width, rect.height) == (width, height) {
+        String::new()
+    } else {
+        format!("crop={}:{}:{}:{},", rect.width, rect.height, rect.x, rect.y)
+    };
+    let (s_width, s_height) = placement.scaled;
+    let scale = format!(",scale={}:{}", s_width, s_height);
+    let (c_width, c_height) = placement.canvas;
+    let pad = placement
+        .offset
+        .map(|(x, y)| format!(",pad={}:{}:{}:{}:black@0", c_width, c_height, x, y))
         .unwrap_or_default();
     let itsscale = if playback.speed_up {
-        3.0 / duration
+        MAX_DURATION / duration
     } else {
         1.0
     };
+    let offset = playback.trim.map(|t| t.offset(duration)).unwrap_or(0.0);
+    // Keep slower clips at their own rate rather than duplicating frames.
+    let fps = info.frame_rate.map_or(MAX_VIDEO_FRAME_RATE, |r| {
+        (r / itsscale).min(MAX_VIDEO_FRAME_RATE)
+    });
+
+    // The native VP8/VP9 decoders of ffmpeg drop the alpha channel, which
+    // WebM keeps in a side stream only libvpx reads.
+    let decoder: &[&str] = match (info.alpha, info.codec.as_str()) {
+        (true, "vp9") => &["-c:v", "libvpx-vp9"],
+        (true, "vp8") => &["-c:v", "libvpx"],
+        _ => &[],
+    };
+    let (itsscale, offset) = (itsscale.to_string(), offset.to_string());
+    let input = path.to_str().expect("path of tempfile");
+    let duration = MAX_DURATION.to_string();
+    let input_args = [
+        &["-itsscale", &itsscale, "-ss", &offset][..],
+        decoder,
+        &["-i", input],
+    ]
+    .concat();
 
-    let vf = format!("format=yuva420p,fps=30{}{}", scale, pad);
+    let key = match effects.key {
+        Some(key) => {
+            let color = match key.color {
+                Some(color) => color,
+                None => corner_color(&first_frame(&input_args).await?),
+            };
+            format!("{},", key_filter(key, color))
+        }
+        None => String::new(),
+    };
+    let filters = format!("{}{}", key, crop);
+    // Clips with an outline or text are drawn frame by frame and encoded
+    // from raw RGBA instead.
+    let drawn = effects.outline.is_some() || effects.text.is_some();
+    let vf = match drawn {
+        false => format!("{}format=yuva420p,fps={}{}{}", filters, fps, scale, pad),
+        true => "format=yuva420p".to_owned(),
+    };
     log::debug!("ffmpeg vf: {}", vf);
 
+    let max_size = match layout {
+        LayoutProp::CustomEmoji => MAX_EMOJI_VIDEO_SIZE,
+        _ => MAX_VIDEO_SIZE,
+    };
+    let mut size = 0;
+    for crf in CRF_LADDER {
+        let crf = crf.to_string();
+        #[rustfmt::skip]
+        let output_args = [
+            "-t", &duration,
+            "-c:v", "libvpx-vp9", "-pix_fmt", "yuva420p", "-b:v", "0", "-crf", &crf,
+            "-an", "-vf", &vf, "-f", "webm", "-",
+        ];
+        // Drawn frames are made again for each try rather than kept around.
+        let stdout = if drawn {
+            let size = (rect.width, rect.height);
+            encode_drawn(
+                &input_args,
+                &filters,
+                fps,
+                &duration,
+                layout,
+                size,
+                effects,
+                &output_args,
+            )
+            .await?
+        } else {
+            ffmpeg(&[&input_args[..], &output_args].concat()).await?
+        };
+        log::debug!("output length with crf {}: {}.", crf, stdout.len());
+        if stdout.len().bytes() <= max_size.kibibytes() {
+            return Ok((stdout, layout, playback));
+        }
+        size = stdout.len();
+    }
+    Err(ConvertError::OutputSize(size as u64, max_size.kibibytes()).into())
+}
+
+/// Validates a Lottie animation against Telegram's constraints and gzips it
+/// into a TGS file. Inputs that are already gzipped are accepted as well.
+fn convert_lottie(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut data = std::fs::read(path)?;
+    if data.starts_with(&[0x1f, 0x8b]) {
+        let mut json = Vec::new();
+        GzDecoder::new(data.as_slice()).read_to_end(&mut json)?;
+        data = json;
+    }
+    let mut lottie: Value =
+        serde_json::from_slice(&data).map_err(|e| ConvertError::Lottie(e.to_string()))?;
+
+    let field = |name: &str| {
+        lottie
+            .get(name)
+            .and_then(Value::as_f64)
+            .ok_or_else(|| ConvertError::Lottie(format!("missing \"{}\"", name)))
+    };
+    let (width, height) = (field("w")? as u32, field("h")? as u32);
+    let frame_rate = field("fr")? as f32;
+    let duration = (field("op")? - field("ip")?) as f32 / frame_rate;
+    log::debug!(
+        "lottie metadata: {}*{}, {}fps, {:.3}s",
+        width,
+        height,
+        frame_rate,
+        duration
+    );
+    anyhow::ensure!(
+        (width, height) == (512, 512),
+        ConvertError::Canvas(width, height)
+    );
+    anyhow::ensure!(
+        frame_rate <= MAX_FRAME_RATE,
+        ConvertError::FrameRate(frame_rate)
+    );
+    anyhow::ensure!(duration <= MAX_DURATION, ConvertError::Duration(duration));
+
+    lottie["tgs"] = 1.into();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&serde_json::to_vec(&lottie)?)?;
+    let converted = encoder.finish()?;
+    log::debug!("output length: {}.", converted.len());
+    anyhow::ensure!(
+        converted.len().bytes() <= MAX_LOTTIE_SIZE.kibibytes(),
+        ConvertError::OutputSize(converted.len() as u64, MAX_LOTTIE_SIZE.kibibytes())
+    );
+    Ok(converted)
+}
+
+/// Re-encodes an image sticker as a PNG, which is the only image format
+/// sticker sets can be built from. WebP conversions are decoded first.
+pub fn sticker_png(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    let (data, _) = encode_image(&img.into_rgba8(), OutputFormat::Png)?;
+    Ok(data)
+}
+
+fn export_image(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    encode_png(&img.into_rgba8())
+}
+
+/// Encodes a clip, given the input args of ffmpeg, as a GIF or an MP4.
+async fn export_video(input_args: &[&str], format: ExportFormat) -> anyhow::Result<Vec<u8>> {
     #[rustfmt::skip]
-    let args =  [
-        "-itsscale", &itsscale.to_string(),
-        "-i", path.to_str().expect("path of tempfile"),
-        "-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "35",
-        "-an", "-vf", &vf, "-f", "webm", "-",
-    ];
-    let Output { stdout, status, .. } = Command::new("ffmpeg")
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .output()
-        .await?;
-    anyhow::ensure!(status.success(), "ffmpeg exited with {:?}", status.code());
-    log::debug!("output length: {}.", stdout.len());
-    Ok((stdout, layout, playback))
+    let output: &[&str] = match format {
+        ExportFormat::Gif => &[
+            "-vf", "split[a][b];[a]palettegen=reserve_transparent=1[p];[b][p]paletteuse",
+            "-f", "gif", "-",
+        ],
+        _ => &[
+            "-c:v", "libx264", "-pix_fmt", "yuv420p", "-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+            "-an", "-movflags", "frag_keyframe+empty_moov", "-f", "mp4", "-",
+        ],
+    };
+    ffmpeg(&[input_args, output].concat()).await
 }
 
-pub async fn convert(
+fn export_lottie(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut json = Vec::new();
+    GzDecoder::new(std::fs::File::open(path)?).read_to_end(&mut json)?;
+    Ok(json)
+}
+
+/// Renders up to `max_frames` frames of a Lottie animation as raw RGBA into a
+/// temporary file, since ffmpeg cannot read Lottie. Returns the file along
+/// with the size and frame rate of the frames.
+#[cfg(feature = "lottie")]
+fn render_lottie(path: &Path, max_frames: usize) -> anyhow::Result<(TempPath, u32, u32, f64)> {
+    let json = export_lottie(path)?;
+    use rlottie::{Animation, Surface};
+
+    let mut animation = Animation::from_data(json, "", "")
+        .ok_or_else(|| ConvertError::Lottie("cannot be rendered".to_owned()))?;
+    let size = animation.size();
+    let mut surface = Surface::new(size);
+    let mut frames = NamedTempFile::new()?;
+    for i in 0..animation.totalframe().min(max_frames) {
+        animation.render(i, &mut surface);
+        // Frames come out as premultiplied BGRA.
+        let mut rgba = Vec::with_capacity(surface.data_as_bytes().len());
+        for p in surface.data_as_bytes().chunks_exact(4) {
+            let (b, g, r, a) = (p[0], p[1], p[2], p[3]);
+            let straight = |c: u8| match a {
+                0 => 0,
+                a => ((u16::from(c) * 255 + u16::from(a) / 2) / u16::from(a)).min(255) as u8,
+            };
+            rgba.extend([straight(r), straight(g), straight(b), a]);
+        }
+        frames.write_all(&rgba)?;
+    }
+    Ok((
+        frames.into_temp_path(),
+        size.width as u32,
+        size.height as u32,
+        animation.framerate(),
+    ))
+}
+
+#[cfg(feature = "lottie")]
+async fn export_animated(path: &Path, format: ExportFormat) -> anyhow::Result<Vec<u8>> {
+    match format {
+        ExportFormat::Json => export_lottie(path),
+        ExportFormat::Png => {
+            let (frame, width, height, _) = render_lottie(path, 1)?;
+            let img = RgbaImage::from_raw(width, height, std::fs::read(&frame)?)
+                .ok_or_else(|| ConvertError::Lottie("has no frames".to_owned()))?;
+            encode_png(&img)
+        }
+        _ => {
+            let (frames, width, height, fps) = render_lottie(path, usize::MAX)?;
+            let (size, fps) = (format!("{}x{}", width, height), fps.to_string());
+            let input = frames.to_str().expect("path of tempfile");
+            #[rustfmt::skip]
+            let input_args = [
+                "-f", "rawvideo", "-pix_fmt", "rgba", "-s", &size, "-r", &fps, "-i", input,
+            ];
+            export_video(&input_args, format).await
+        }
+    }
+}
+
+/// Turns a sticker back into a format other apps can edit.
+pub async fn export(
     bot: &AutoSend<Bot>,
     file_id: &str,
     media_type: MediaType,
-    layout: Option<LayoutProp>,
-    playback: Option<PlaybackProp>,
-) -> Result<(InputFile, LayoutProp, Option<PlaybackProp>), ConvertError> {
-    let TgFile {
-        file_path,
-        file_size,
-        ..
-    } = bot.get_file(file_id).await.map_err(ConvertError::wrap)?;
-    if file_size.bytes() > 5.mebibytes() {
-        return Err(ConvertError::FileSize(file_size as u64));
+    format: ExportFormat,
+) -> Result<Vec<u8>, ConvertError> {
+    let tmp_path = download(bot, file_id).await?;
+    log::debug!("export a {} sticker as {}...", media_type, format);
+
+    match media_type {
+        MediaType::Image => export_image(&tmp_path),
+        MediaType::Video => {
+            // Only libvpx reads the alpha channel of VP9 stickers.
+            let input = tmp_path.to_str().expect("path of tempfile");
+            export_video(&["-c:v", "libvpx-vp9", "-i", input], format).await
+        }
+        #[cfg(feature = "lottie")]
+        MediaType::Animated => export_animated(&tmp_path, format).await,
+        #[cfg(not(feature = "lottie"))]
+        MediaType::Animated => export_lottie(&tmp_path),
     }
+    .map_err(ConvertError::wrap)
+}
 
+/// Looks a file up, rejecting those too large to convert.
+pub async fn get_file(bot: &AutoSend<Bot>, file_id: &str) -> Result<TgFile, ConvertError> {
+    let file = bot.get_file(file_id).await.map_err(ConvertError::wrap)?;
+    if file.file_size.bytes() > 5.mebibytes() {
+        return Err(ConvertError::FileSize(file.file_size as u64));
+    }
+    Ok(file)
+}
+
+pub async fn download_file(bot: &AutoSend<Bot>, file: &TgFile) -> Result<TempPath, ConvertError> {
     let (tmp_file, tmp_path) = NamedTempFile::new()
         .expect("tempfile is created")
         .into_parts();
     let mut tmp_file: File = tmp_file.into();
-    bot.download_file(&file_path, &mut tmp_file)
+    bot.download_file(&file.file_path, &mut tmp_file)
         .await
         .map_err(ConvertError::wrap)?;
+    Ok(tmp_path)
+}
+
+pub async fn download(bot: &AutoSend<Bot>, file_id: &str) -> Result<TempPath, ConvertError> {
+    let file = get_file(bot, file_id).await?;
+    download_file(bot, &file).await
+}
+
+/// Identifies what the encoder makes of a media type, so that cached results
+/// of older encoders or other formats are not sent again.
+pub fn encoder(media_type: MediaType, settings: Settings) -> String {
+    match media_type {
+        MediaType::Image => format!("{}-{}", ENCODER_VERSION, settings.format.extension()),
+        _ => ENCODER_VERSION.to_string(),
+    }
+}
 
-    let (file_name, data, layout, playback) = match media_type {
+pub async fn convert(
+    bot: &AutoSend<Bot>,
+    source: &Source,
+    media_type: MediaType,
+    layout: Option<LayoutProp>,
+    playback: Option<PlaybackProp>,
+    effects: &Effects,
+    settings: Settings,
+) -> Result<Sticker, ConvertError> {
+    let path = source.path(bot).await?;
+
+    let (ext, data, layout, playback, translucent) = match detect_media_type(path, media_type) {
         MediaType::Image => {
-            let (data, layout) = convert_image(&tmp_path, layout).map_err(ConvertError::wrap)?;
-            ("sticker.png", data, layout, None)
+            let (data, layout, format, translucent) =
+                convert_image(path, layout, effects, settings).map_err(ConvertError::wrap)?;
+            (format.extension(), data, layout, None, Some(translucent))
         }
         MediaType::Video => {
-            let (data, layout, playback) = convert_video(&tmp_path, layout, playback)
-                .await
-                .map_err(ConvertError::wrap)?;
-            ("sticker.webm", data, layout, Some(playback))
+            let info = source.info(bot).await?;
+            let (data, layout, playback) =
+                convert_video(path, info, layout, playback, effects, settings)
+                    .await
+                    .map_err(ConvertError::wrap)?;
+            // Only a chroma key cuts holes into a clip; the other effects
+            // draw over it.
+            let translucent = info.alpha || effects.key.is_some();
+            ("webm", data, layout, Some(playback), Some(translucent))
+        }
+        MediaType::Animated => {
+            let data = convert_lottie(path).map_err(ConvertError::wrap)?;
+            ("tgs", data, LayoutProp::Large, None, None)
         }
     };
 
-    Ok((
-        InputFile::memory(data).file_name(file_name),
+    Ok(Sticker {
+        size: data.len() as u64,
+        file: InputFile::memory(data).file_name(format!("sticker.{}", ext)),
         layout,
         playback,
-    ))
+        translucent,
+    })
+}
+
+fn archive_media_type(name: &str) -> Option<MediaType> {
+    let (_, ext) = name.rsplit_once('.')?;
+    match ext.to_lowercase().as_str() {
+        "png" | "jpg" | "jpeg" | "webp" | "bmp" => Some(MediaType::Image),
+        "gif" | "mp4" | "webm" | "mov" | "mkv" => Some(MediaType::Video),
+        "json" | "tgs" => Some(MediaType::Animated),
+        _ => None,
+    }
+}
+
+/// Extracts the `i`-th entry of an archive into a temporary file.
+fn extract(archive: &mut ZipArchive<std::fs::File>, i: usize) -> anyhow::Result<TempPath> {
+    let entry = archive.by_index(i)?;
+    let limit = 5.mebibytes().as_u64();
+    if entry.size() > limit {
+        return Err(ConvertError::FileSize(entry.size()).into());
+    }
+    // The declared size may lie, so never inflate more than the limit.
+    let mut tmp_file = NamedTempFile::new()?;
+    let size = std::io::copy(&mut entry.take(limit + 1), &mut tmp_file)?;
+    anyhow::ensure!(size <= limit, ConvertError::FileSize(size));
+    Ok(tmp_file.into_temp_path())
+}
+
+/// Converts every image, clip and animation in a ZIP archive with the given
+/// settings and packs the results into a new archive along with a report.
+/// Each entry waits for a worker of the queue on its own, so that a large
+/// archive does not hold one up for long. Returns the archive, the number of
+/// entries and the errors of those that failed.
+pub async fn convert_archive(
+    bot: &AutoSend<Bot>,
+    queue: &Queue,
+    user_id: Option<i64>,
+    file_id: &str,
+    settings: Settings,
+) -> Result<(Vec<u8>, usize, Vec<(String, ConvertError)>), ConvertError> {
+    let tmp_path = download(bot, file_id).await?;
+    let mut archive = std::fs::File::open(&tmp_path)
+        .map_err(ConvertError::wrap)
+        .and_then(|f| ZipArchive::new(f).map_err(ConvertError::wrap))?;
+
+    let entries: Vec<_> = (0..archive.len())
+        .filter_map(|i| {
+            let name = archive.by_index_raw(i).ok()?.name().to_owned();
+            let media_type = archive_media_type(&name)?;
+            (!name.starts_with("__MACOSX/")).then_some((i, name, media_type))
+        })
+        .collect();
+    if entries.len() > MAX_ARCHIVE_ENTRIES {
+        return Err(ConvertError::ArchiveEntries(
+            entries.len(),
+            MAX_ARCHIVE_ENTRIES,
+        ));
+    }
+    log::debug!("convert {} files of an archive...", entries.len());
+    let total = entries.len();
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut names = HashSet::new();
+    let mut errors = Vec::new();
+    let effects = Effects::default();
+    for (i, name, media_type) in entries {
+        let mut ticket = queue.join(user_id);
+        ticket.ready().await;
+        let r = async {
+            let tmp_path = extract(&mut archive, i)?;
+            let (data, ext) = match detect_media_type(&tmp_path, media_type) {
+                MediaType::Image => {
+                    let (data, _, format, _) = convert_image(&tmp_path, None, &effects, settings)?;
+                    (data, format.extension())
+                }
+                MediaType::Video => {
+                    let info = probe(&tmp_path).await?;
+                    let (data, _, _) =
+                        convert_video(&tmp_path, &info, None, None, &effects, settings).await?;
+                    (data, "webm")
+                }
+                MediaType::Animated => (convert_lottie(&tmp_path)?, "tgs"),
+            };
+            anyhow::Ok((data, ext))
+        }
+        .await;
+
+        match r {
+            Ok((data, ext)) => {
+                let file_name = name.rsplit('/').next().unwrap_or(&name);
+                let stem = file_name.rsplit_once('.').map_or(file_name, |(s, _)| s);
+                let mut out = format!("{}.{}", stem, ext);
+                for n in 1.. {
+                    if names.insert(out.clone()) {
+                        break;
+                    }
+                    out = format!("{}_{}.{}", stem, n, ext);
+                }
+                zip.start_file(&out, FileOptions::default())
+                    .map_err(ConvertError::wrap)?;
+                zip.write_all(&data).map_err(ConvertError::wrap)?;
+            }
+            Err(e) => errors.push((name, ConvertError::wrap(e))),
+        }
+        drop(ticket);
+    }
+
+    let report: String = errors
+        .iter()
+        .map(|(name, e)| format!("{}: {}\n", name, e))
+        .collect();
+    zip.start_file("report.txt", FileOptions::default())
+        .and_then(|_| Ok(zip.write_all(report.as_bytes())?))
+        .map_err(ConvertError::wrap)?;
+    let data = zip.finish().map_err(ConvertError::wrap)?.into_inner();
+    Ok((data, total, errors))
 }
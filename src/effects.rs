@@ -0,0 +1,317 @@
+use crate::{
+    errors::*,
+    types::{ChromaKey, Outline, TextOverlay, TextPosition, TextStyle},
+};
+use ab_glyph::{point, Font, FontVec, PxScaleFont, ScaleFont};
+use image::{GenericImage, Rgba, RgbaImage};
+use std::{env, sync::OnceLock};
+
+/// Width of the soft edge of a chroma key, on the same scale as its
+/// similarity. Colours this much beyond the tolerance are fully kept.
+const KEY_BLEND: f32 = 0.05;
+/// Font of overlay text unless `TEXT_FONT` names another one. It is built
+/// in, as hosts like Heroku have no fonts installed.
+const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/DejaVuSans-Bold.ttf");
+/// Overlay text is shrunk to take at most this share of the height, but
+/// never below `MIN_TEXT_SIZE` pixels.
+const MAX_TEXT_HEIGHT: f32 = 1.0 / 3.0;
+const MIN_TEXT_SIZE: f32 = 10.0;
+
+/// Distance of two colours from 0 to 1, the way the `colorkey` filter of
+/// ffmpeg measures it.
+fn distance(a: [u8; 3], b: [u8; 3]) -> f32 {
+    let sum: f32 = a
+        .iter()
+        .zip(b)
+        .map(|(&x, y)| (f32::from(x) - f32::from(y)).powi(2))
+        .sum();
+    (sum / (255.0 * 255.0 * 3.0)).sqrt()
+}
+
+fn rgb(p: &image::Rgba<u8>) -> [u8; 3] {
+    [p[0], p[1], p[2]]
+}
+
+/// Colours up to this distance from the key are removed.
+fn similarity(key: ChromaKey) -> f32 {
+    // ffmpeg rejects a similarity of 0.
+    f32::from(key.tolerance.max(1)) / 100.0
+}
+
+/// The colour of the background, taken from the corner that is closest to
+/// the other three, so one covered by the subject does not count.
+pub fn corner_color(img: &RgbaImage) -> [u8; 3] {
+    let (x, y) = (img.width() - 1, img.height() - 1);
+    let corners = [(0, 0), (x, 0), (0, y), (x, y)].map(|(x, y)| rgb(img.get_pixel(x, y)));
+    corners
+        .into_iter()
+        .min_by(|&a, &b| {
+            let spread = |c| corners.iter().map(|&o| distance(c, o)).sum::<f32>();
+            spread(a).total_cmp(&spread(b))
+        })
+        .expect("an image has corners")
+}
+
+/// Makes the key colour of the image transparent, fading out colours close
+/// to it.
+pub fn apply_key(img: &mut RgbaImage, key: ChromaKey) {
+    let color = key.color.unwrap_or_else(|| corner_color(img));
+    let similarity = similarity(key);
+    for p in img.pixels_mut() {
+        let keep = ((distance(rgb(p), color) - similarity) / KEY_BLEND).clamp(0.0, 1.0);
+        p[3] = (f32::from(p[3]) * keep).round() as u8;
+    }
+}
+
+/// The ffmpeg filter removing `color` as the key says.
+pub fn key_filter(key: ChromaKey, color: [u8; 3]) -> String {
+    let [r, g, b] = color;
+    format!(
+        "colorkey=0x{:02x}{:02x}{:02x}:{}:{}",
+        r,
+        g,
+        b,
+        similarity(key),
+        KEY_BLEND
+    )
+}
+
+/// Width of the outline in pixels of a sticker fitted in a box of the size.
+/// It is at most a quarter of the shorter side of the box, so the outlines
+/// on both sides of low boxes leave half of it for the media.
+pub fn outline_width(outline: Outline, b_width: u32, b_height: u32) -> u32 {
+    let width = u32::from(outline.width) * b_width.max(b_height) / 512;
+    width.min(b_width.min(b_height) / 4).max(1)
+}
+
+/// Squared distances to the nearest point of a sampled function, in one
+/// dimension, by the method of Felzenszwalb and Huttenlocher.
+fn distance_1d(f: &[f64], d: &mut [f64]) {
+    let n = f.len();
+    let mut v = vec![0; n];
+    let mut z = vec![0.0; n + 1];
+    let mut k = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+    for q in 1..n {
+        let parabola = |p: usize| f[p] + (p * p) as f64;
+        let mut s;
+        loop {
+            let p = v[k];
+            s = (parabola(q) - parabola(p)) / (2 * (q - p)) as f64;
+            if s > z[k] {
+                break;
+            }
+            k -= 1;
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f64::INFINITY;
+    }
+    k = 0;
+    for (q, d) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let p = v[k];
+        *d = (q.abs_diff(p) * q.abs_diff(p)) as f64 + f[p];
+    }
+}
+
+/// Squared distance of every pixel to the nearest one set in the mask.
+fn distance_transform(mask: &[bool], width: usize, height: usize) -> Vec<f64> {
+    // Anything farther than the image is wide or high is as good as never.
+    let far = ((width * width + height * height) * 4) as f64;
+    let mut d: Vec<f64> = mask.iter().map(|&m| if m { 0.0 } else { far }).collect();
+    let mut f = vec![0.0; width.max(height)];
+    let mut out = vec![0.0; width.max(height)];
+    for x in 0..width {
+        for y in 0..height {
+            f[y] = d[y * width + x];
+        }
+        distance_1d(&f[..height], &mut out[..height]);
+        for y in 0..height {
+            d[y * width + x] = out[y];
+        }
+    }
+    for row in d.chunks_exact_mut(width) {
+        f[..width].copy_from_slice(row);
+        distance_1d(&f[..width], &mut out[..width]);
+        row.copy_from_slice(&out[..width]);
+    }
+    d
+}
+
+/// Puts a colour of some opacity over another one.
+fn over(top: [u8; 3], top_alpha: f64, bottom: [u8; 3], bottom_alpha: f64) -> Rgba<u8> {
+    let alpha = top_alpha + bottom_alpha * (1.0 - top_alpha);
+    let channel = |t: u8, b: u8| {
+        let mixed = f64::from(t) * top_alpha + f64::from(b) * bottom_alpha * (1.0 - top_alpha);
+        (mixed / alpha).round() as u8
+    };
+    if alpha > 0.0 {
+        let [r, g, b] = [0, 1, 2].map(|i| channel(top[i], bottom[i]));
+        Rgba([r, g, b, (alpha * 255.0).round() as u8])
+    } else {
+        Rgba([0; 4])
+    }
+}
+
+/// Opacity of a border of `width` pixels at a squared distance from what it
+/// surrounds. Its edge is antialiased over a pixel.
+fn border_alpha(width: f64, distance: f64) -> f64 {
+    (width + 0.5 - distance.sqrt()).clamp(0.0, 1.0)
+}
+
+/// Draws an outline of `width` pixels around the opaque parts of the image,
+/// growing it by as much on every side.
+pub fn add_outline(img: &RgbaImage, color: [u8; 3], width: u32) -> RgbaImage {
+    let mut canvas = RgbaImage::new(img.width() + 2 * width, img.height() + 2 * width);
+    canvas
+        .copy_from(img, width, width)
+        .expect("canvas is larger than the image");
+
+    let mask: Vec<bool> = canvas.pixels().map(|p| p[3] >= 128).collect();
+    let (w, h) = canvas.dimensions();
+    let distances = distance_transform(&mask, w as usize, h as usize);
+    for (p, d) in canvas.pixels_mut().zip(distances) {
+        let line = border_alpha(f64::from(width), d);
+        *p = over(rgb(p), f64::from(p[3]) / 255.0, color, line);
+    }
+    canvas
+}
+
+type ScaledFont<'a> = PxScaleFont<&'a FontVec>;
+
+/// The font of overlay text, loaded on first use.
+fn font() -> Result<&'static FontVec, ConvertError> {
+    static FONT: OnceLock<Option<FontVec>> = OnceLock::new();
+    FONT.get_or_init(|| {
+        let path = env::var("TEXT_FONT").ok();
+        let data = match &path {
+            Some(path) => std::fs::read(path).map_err(anyhow::Error::from),
+            None => Ok(DEFAULT_FONT.to_vec()),
+        };
+        let font = data.and_then(|data| Ok(FontVec::try_from_vec(data)?));
+        font.map_err(|e| {
+            let path = path.as_deref().unwrap_or("DejaVuSans-Bold.ttf");
+            log::error!("Failed to load the font {}: {}", path, e)
+        })
+        .ok()
+    })
+    .as_ref()
+    .ok_or(ConvertError::Font)
+}
+
+fn line_width(font: &ScaledFont, line: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in line.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// Breaks the text into lines at spaces so they are at most `max_width`
+/// wide, except for words that are wider on their own.
+fn wrap(font: &ScaledFont, text: &str, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if line.is_empty() {
+                line.push_str(word);
+            } else if line_width(font, &format!("{} {}", line, word)) <= max_width {
+                line.push(' ');
+                line.push_str(word);
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_owned()));
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Renders the overlay text on a transparent layer of the given size, as
+/// large as it fits.
+pub fn render_text(
+    width: u32,
+    height: u32,
+    overlay: &TextOverlay,
+) -> Result<RgbaImage, ConvertError> {
+    let font = font()?;
+    let text = match overlay.style {
+        TextStyle::Meme => overlay.text.to_uppercase(),
+        _ => overlay.text.clone(),
+    };
+    let margin = (width.min(height) as f32 / 24.0).max(1.0);
+    let max_width = width as f32 - 2.0 * margin;
+
+    let mut size = height as f32 * MAX_TEXT_HEIGHT;
+    let (scaled, lines) = loop {
+        let scaled = font.as_scaled(size);
+        let lines = wrap(&scaled, &text, max_width);
+        let fits = lines.len() as f32 * scaled.height() <= height as f32 * MAX_TEXT_HEIGHT
+            && lines
+                .iter()
+                .all(|line| line_width(&scaled, line) <= max_width);
+        if fits || size <= MIN_TEXT_SIZE {
+            break (scaled, lines);
+        }
+        size = (size * 0.9).max(MIN_TEXT_SIZE);
+    };
+
+    let (w, h) = (width as usize, height as usize);
+    let mut coverage = vec![0.0; w * h];
+    let line_height = scaled.height() + scaled.line_gap();
+    let block = lines.len() as f32 * line_height - scaled.line_gap();
+    let top = match overlay.position {
+        TextPosition::Top => margin,
+        TextPosition::Bottom => height as f32 - margin - block,
+    };
+    for (i, line) in lines.iter().enumerate() {
+        let mut x = (width as f32 - line_width(&scaled, line)) / 2.0;
+        let baseline = top + i as f32 * line_height + scaled.ascent();
+        let mut previous = None;
+        for c in line.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                x += scaled.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(size, point(x, baseline));
+            x += scaled.h_advance(id);
+            previous = Some(id);
+
+            let outlined = match font.outline_glyph(glyph) {
+                Some(outlined) => outlined,
+                None => continue,
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|gx, gy, c| {
+                let x = bounds.min.x as i64 + i64::from(gx);
+                let y = bounds.min.y as i64 + i64::from(gy);
+                if (0..w as i64).contains(&x) && (0..h as i64).contains(&y) {
+                    let v = &mut coverage[y as usize * w + x as usize];
+                    *v = (*v + f64::from(c)).min(1.0);
+                }
+            });
+        }
+    }
+
+    let mask: Vec<bool> = coverage.iter().map(|&c| c >= 0.5).collect();
+    let distances = distance_transform(&mask, w, h);
+    let border = f64::from((size / 12.0).max(1.0));
+    let (fill, stroke) = overlay.style.colors();
+    let mut layer = RgbaImage::new(width, height);
+    for ((p, c), d) in layer.pixels_mut().zip(coverage).zip(distances) {
+        *p = over(fill, c, stroke, border_alpha(border, d));
+    }
+    Ok(layer)
+}
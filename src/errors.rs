@@ -1,3 +1,4 @@
+use crate::types::{ExportFormat, MediaType};
 use thiserror::Error;
 use ubyte::ByteUnit;
 
@@ -7,6 +8,8 @@ pub enum CallbackError {
     Unknown(String),
     #[error("Incompatible callback.")]
     Incompatible,
+    #[error(transparent)]
+    Props(#[from] PropsError),
 }
 
 #[derive(Debug, Error)]
@@ -15,10 +18,26 @@ pub enum ConvertError {
     Duration(f32),
     #[error("File size too large: {:.3} exceeds 5MiB.", ByteUnit::Byte(*.0))]
     FileSize(u64),
+    #[error("Sticker size too large: {:.3} exceeds {} even at the lowest quality.", ByteUnit::Byte(*.0), .1)]
+    OutputSize(u64, ByteUnit),
     #[error("Failed to read the video's {0} from \"{1}\".")]
     Format(String, String),
+    #[error("Not a Lottie animation: {0}.")]
+    Lottie(String),
+    #[error("Animation canvas is {0}px*{1}px, but must be 512px*512px.")]
+    Canvas(u32, u32),
+    #[error("Frame rate too high: {0}fps exceeds 60fps.")]
+    FrameRate(f32),
     #[error("Invalid media type.")]
     MediaType,
+    #[error("Too many files in the archive: {0} exceeds {1}.")]
+    ArchiveEntries(usize, usize),
+    #[error("{0} timed out after {1:.0}s.")]
+    Timeout(String, f32),
+    #[error("Cancelled by a newer conversion.")]
+    Cancelled,
+    #[error("No font to draw text with.")]
+    Font,
     #[error("Internal error: {0}")]
     Internal(anyhow::Error),
 }
@@ -39,4 +58,24 @@ pub enum PropsError {
     Message,
     #[error("Failed to get the original media.")]
     Origin,
+    #[error("Not a list of emoji: {0}.")]
+    Emoji(String),
+    #[error("Too many emoji for one sticker.")]
+    EmojiLength,
+    #[error("Not a colour: {0}. Send it as hex, e.g. #00ff00.")]
+    Color(String),
+    #[error("Text too long: {0} characters exceed {1}.")]
+    TextLength(usize, usize),
+}
+
+#[derive(Debug, Error)]
+pub enum StickerSetError {
+    #[error("Please reply to a converted sticker with this command.")]
+    Reply,
+    #[error("Cannot tell who sent the command.")]
+    User,
+    #[error("Sticker set \"{1}\" does not accept {0} stickers.")]
+    Incompatible(MediaType, String),
+    #[error("Cannot export {1} stickers as {}.", .0.text())]
+    Export(ExportFormat, MediaType),
 }
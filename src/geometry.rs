@@ -0,0 +1,464 @@
+//! Where media goes in a sticker: the part of it to keep, the size it is
+//! scaled to and its offset in the sticker. Nothing here touches pixels, so
+//! the conversions of images and of clips agree on the same numbers.
+
+use crate::types::{Crop, MAX_NUDGE};
+
+/// Telegram wants one side of a sticker to be exactly this long, and the
+/// other no longer.
+pub const STICKER_SIDE: u32 = 512;
+
+/// A part of the media, in its pixels.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where media shorter than its box goes along one side of it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+/// The box a layout fits media in.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// How to align media in the box, if it is padded to fill the box.
+    pub pad: Option<(Anchor, Anchor)>,
+}
+
+/// Where media ends up in a sticker.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Placement {
+    /// The part of the media to keep.
+    pub crop: Rect,
+    /// The box the kept part is fitted in.
+    pub bounds: (u32, u32),
+    /// The size the kept part is scaled to.
+    pub scaled: (u32, u32),
+    /// The size of the sticker.
+    pub canvas: (u32, u32),
+    /// Where the scaled media goes in the sticker, if it is padded.
+    pub offset: Option<(u32, u32)>,
+}
+
+impl Anchor {
+    /// Offset of something `size` long in `room`, or 0 if it does not fit.
+    pub fn offset(self, size: u32, room: u32) -> u32 {
+        let free = room.saturating_sub(size);
+        match self {
+            Self::Start => 0,
+            Self::Middle => free / 2,
+            Self::End => free,
+        }
+    }
+}
+
+/// `a * num / den` rounded to the nearest whole number, without overflowing.
+fn scale(a: u32, num: u32, den: u32) -> u32 {
+    let (a, num, den) = (u64::from(a), u64::from(num), u64::from(den.max(1)));
+    ((a * num + den / 2) / den).min(u64::from(u32::MAX)) as u32
+}
+
+/// Size of media scaled to fit in a box, keeping its aspect ratio to the
+/// nearest pixel. One side matches the box, and neither is 0.
+pub fn fit(width: u32, height: u32, b_width: u32, b_height: u32) -> (u32, u32) {
+    let (width, height) = (width.max(1), height.max(1));
+    let (b_width, b_height) = (b_width.max(1), b_height.max(1));
+    if u64::from(width) * u64::from(b_height) >= u64::from(height) * u64::from(b_width) {
+        (b_width, scale(height, b_width, width).clamp(1, b_height))
+    } else {
+        (scale(width, b_height, height).clamp(1, b_width), b_height)
+    }
+}
+
+/// Offset of media of the given size in the frame, if the frame is padded
+/// around it.
+pub fn offset(frame: Frame, width: u32, height: u32) -> Option<(u32, u32)> {
+    let (x, y) = frame.pad?;
+    if (width, height) == (frame.width, frame.height) {
+        return None;
+    }
+    Some((x.offset(width, frame.width), y.offset(height, frame.height)))
+}
+
+/// The part of media of the given size that is kept in the frame.
+pub fn crop(width: u32, height: u32, frame: Frame, crop: Crop) -> Rect {
+    let (width, height) = (width.max(1), height.max(1));
+    let (c_width, c_height) = match crop {
+        Crop::Fit => (width, height),
+        // The largest part shaped like the box, rounded down so that it is
+        // never narrower or shorter than the box once scaled.
+        Crop::Fill => {
+            let (b_width, b_height) = (frame.width.max(1), frame.height.max(1));
+            let (w, h) = (u64::from(width), u64::from(height));
+            if w * u64::from(b_height) >= h * u64::from(b_width) {
+                let c_width = h * u64::from(b_width) / u64::from(b_height);
+                ((c_width as u32).max(1), height)
+            } else {
+                let c_height = w * u64::from(b_height) / u64::from(b_width);
+                (width, (c_height as u32).max(1))
+            }
+        }
+        Crop::Square => (width.min(height), width.min(height)),
+        Crop::Manual { .. } => {
+            let zoom = f64::from(crop.zoom());
+            let side = |x: u32| ((f64::from(x) / zoom) as u32).clamp(1, x);
+            (side(width), side(height))
+        }
+    };
+    // Manual crops move from the centre by steps of the room left to the
+    // edges, and the others stay there.
+    let (x, y) = match crop {
+        Crop::Manual { x, y, .. } => (x, y),
+        _ => (0, 0),
+    };
+    let start = |room: u32, step: i8| {
+        let max = MAX_NUDGE.unsigned_abs();
+        let steps = step.clamp(-MAX_NUDGE, MAX_NUDGE).saturating_add(MAX_NUDGE) as u8;
+        (u64::from(room) * u64::from(steps) / u64::from(2 * max)) as u32
+    };
+    Rect {
+        x: start(width - c_width, x),
+        y: start(height - c_height, y),
+        width: c_width,
+        height: c_height,
+    }
+}
+
+/// Where media of the given size goes in the frame, once cropped.
+pub fn place(width: u32, height: u32, frame: Frame, crop: Crop) -> Placement {
+    let rect = self::crop(width, height, frame, crop);
+    let scaled = fit(rect.width, rect.height, frame.width, frame.height);
+    let offset = offset(frame, scaled.0, scaled.1);
+    let canvas = match offset {
+        Some(_) => (frame.width, frame.height),
+        None => scaled,
+    };
+    Placement {
+        crop: rect,
+        bounds: (frame.width, frame.height),
+        scaled,
+        canvas,
+        offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn fit_wide_media_to_the_width() {
+        assert_eq!(fit(1024, 256, 512, 512), (512, 128));
+        assert_eq!(fit(2048, 128, 512, 128), (512, 32));
+    }
+
+    #[test]
+    fn fit_tall_media_to_the_height() {
+        assert_eq!(fit(100, 400, 512, 512), (128, 512));
+        assert_eq!(fit(100, 1000, 512, 256), (26, 256));
+    }
+
+    #[test]
+    fn fit_scales_small_media_up() {
+        assert_eq!(fit(10, 10, 512, 128), (128, 128));
+        assert_eq!(fit(1, 1, 512, 512), (512, 512));
+    }
+
+    #[test]
+    fn fit_does_not_overflow() {
+        assert_eq!(fit(u32::MAX, u32::MAX - 1, 512, 512), (512, 512));
+        assert_eq!(fit(u32::MAX, 1, 512, 512), (512, 1));
+        assert_eq!(fit(1, u32::MAX, 512, 512), (1, 512));
+    }
+
+    #[test]
+    fn fit_treats_empty_sides_as_one_pixel() {
+        assert_eq!(fit(0, 0, 512, 512), (512, 512));
+        assert_eq!(fit(100, 0, 512, 512), (512, 5));
+    }
+
+    #[test]
+    fn anchors_do_not_underflow() {
+        for anchor in [Anchor::Start, Anchor::Middle, Anchor::End] {
+            assert_eq!(anchor.offset(600, 512), 0);
+        }
+        assert_eq!(Anchor::Middle.offset(100, 512), 206);
+        assert_eq!(Anchor::End.offset(100, 512), 412);
+    }
+
+    #[test]
+    fn small_narrow_media_is_padded_sideways() {
+        let layout = LayoutProp::Small(Alignment::Left, VerticalAlignment::Middle);
+        let placement = layout.resize(256, 128, Crop::Fit);
+        assert_eq!(placement.scaled, (256, 128));
+        assert_eq!(placement.offset, Some((0, 0)));
+        assert_eq!(placement.canvas, (512, 128));
+
+        let layout = LayoutProp::Small(Alignment::Center, VerticalAlignment::Middle);
+        assert_eq!(layout.resize(256, 128, Crop::Fit).offset, Some((128, 0)));
+        let layout = LayoutProp::Small(Alignment::Right, VerticalAlignment::Middle);
+        assert_eq!(layout.resize(256, 128, Crop::Fit).offset, Some((256, 0)));
+    }
+
+    #[test]
+    fn fill_rounds_the_crop_down() {
+        let layout = LayoutProp::Height(32, Alignment::Left, VerticalAlignment::Top);
+        let placement = layout.resize(520, 512, Crop::Fill);
+        assert_eq!(placement.crop.height, 32);
+        assert_eq!(placement.scaled, (512, 32));
+    }
+
+    #[test]
+    fn small_wide_media_is_aligned_vertically() {
+        let wide = |v| {
+            LayoutProp::Small(Alignment::Center, v)
+                .resize(2048, 128, Crop::Fit)
+                .offset
+        };
+        assert_eq!(wide(VerticalAlignment::Top), Some((0, 0)));
+        assert_eq!(wide(VerticalAlignment::Middle), Some((0, 48)));
+        assert_eq!(wide(VerticalAlignment::Bottom), Some((0, 96)));
+    }
+
+    #[test]
+    fn medium_tall_media_is_aligned() {
+        let layout = LayoutProp::Medium(Alignment::Center, VerticalAlignment::Bottom);
+        let placement = layout.resize(100, 1000, Crop::Fit);
+        assert_eq!(placement.scaled, (26, 256));
+        assert_eq!(placement.offset, Some((243, 0)));
+        assert_eq!(placement.canvas, (512, 256));
+    }
+
+    #[test]
+    fn media_filling_the_box_is_not_padded() {
+        let layout = LayoutProp::Medium(Alignment::Center, VerticalAlignment::Middle);
+        let placement = layout.resize(1024, 512, Crop::Fit);
+        assert_eq!(placement.offset, None);
+        assert_eq!(placement.canvas, (512, 256));
+    }
+
+    #[test]
+    fn large_media_is_never_padded() {
+        let placement = LayoutProp::Large.resize(300, 600, Crop::Fit);
+        assert_eq!(placement.scaled, (256, 512));
+        assert_eq!(placement.offset, None);
+        assert_eq!(placement.canvas, (256, 512));
+    }
+
+    #[test]
+    fn custom_emoji_are_centered() {
+        let placement = LayoutProp::CustomEmoji.resize(200, 100, Crop::Fit);
+        assert_eq!(placement.scaled, (100, 50));
+        assert_eq!(placement.offset, Some((0, 25)));
+        assert_eq!(placement.canvas, (EMOJI_SIZE, EMOJI_SIZE));
+    }
+
+    #[test]
+    fn free_heights_are_padded() {
+        let layout = LayoutProp::Height(32, Alignment::Center, VerticalAlignment::Middle);
+        let placement = layout.resize(512, 512, Crop::Fit);
+        assert_eq!(placement.scaled, (32, 32));
+        assert_eq!(placement.offset, Some((240, 0)));
+        assert_eq!(placement.canvas, (512, 32));
+    }
+
+    #[test]
+    fn fill_crops_to_the_box() {
+        let crop = LayoutProp::Large.resize(1000, 500, Crop::Fill).crop;
+        assert_eq!(
+            crop,
+            Rect {
+                x: 250,
+                y: 0,
+                width: 500,
+                height: 500
+            }
+        );
+        let layout = LayoutProp::Small(Alignment::Center, VerticalAlignment::Middle);
+        let placement = layout.resize(1000, 1000, Crop::Fill);
+        assert_eq!(
+            placement.crop,
+            Rect {
+                x: 0,
+                y: 375,
+                width: 1000,
+                height: 250
+            }
+        );
+        assert_eq!(placement.canvas, (512, 128));
+    }
+
+    #[test]
+    fn square_crops_the_middle() {
+        let crop = LayoutProp::Large.resize(300, 200, Crop::Square).crop;
+        assert_eq!(
+            crop,
+            Rect {
+                x: 50,
+                y: 0,
+                width: 200,
+                height: 200
+            }
+        );
+    }
+
+    #[test]
+    fn manual_crops_move_to_the_edges() {
+        let manual = |x, y| {
+            let crop = Crop::Manual { zoom: 2, x, y };
+            LayoutProp::Large.resize(1000, 1000, crop).crop
+        };
+        let window = |x, y| Rect {
+            x,
+            y,
+            width: 500,
+            height: 500,
+        };
+        assert_eq!(manual(0, 0), window(250, 250));
+        assert_eq!(manual(-MAX_NUDGE, 0), window(0, 250));
+        assert_eq!(manual(MAX_NUDGE, MAX_NUDGE), window(500, 500));
+        assert_eq!(manual(1, -1), window(300, 200));
+    }
+
+    #[test]
+    fn manual_crops_without_zoom_keep_everything() {
+        let crop = Crop::Manual {
+            zoom: 0,
+            x: MAX_NUDGE,
+            y: -MAX_NUDGE,
+        };
+        let crop = LayoutProp::Large.resize(640, 480, crop).crop;
+        assert_eq!(
+            crop,
+            Rect {
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 480
+            }
+        );
+    }
+
+    fn layouts() -> impl Strategy<Value = LayoutProp> {
+        let alignment = prop_oneof![
+            Just(Alignment::Left),
+            Just(Alignment::Center),
+            Just(Alignment::Right),
+        ];
+        let vertical = prop_oneof![
+            Just(VerticalAlignment::Top),
+            Just(VerticalAlignment::Middle),
+            Just(VerticalAlignment::Bottom),
+        ];
+        prop_oneof![
+            (alignment.clone(), vertical.clone()).prop_map(|(p, v)| LayoutProp::Small(p, v)),
+            (alignment.clone(), vertical.clone()).prop_map(|(p, v)| LayoutProp::Medium(p, v)),
+            (1..=16u32, alignment, vertical).prop_map(|(h, p, v)| LayoutProp::Height(h * 32, p, v)),
+            Just(LayoutProp::Large),
+            Just(LayoutProp::CustomEmoji),
+        ]
+    }
+
+    fn crops() -> impl Strategy<Value = Crop> {
+        prop_oneof![
+            Just(Crop::Fit),
+            Just(Crop::Fill),
+            Just(Crop::Square),
+            (0..=MAX_ZOOM, -MAX_NUDGE..=MAX_NUDGE, -MAX_NUDGE..=MAX_NUDGE)
+                .prop_map(|(zoom, x, y)| Crop::Manual { zoom, x, y }),
+        ]
+    }
+
+    /// Mostly the sizes of real media, but sometimes any size at all.
+    fn sides() -> impl Strategy<Value = u32> {
+        prop_oneof![4 => 1..=4096u32, 1 => 1..=u32::MAX]
+    }
+
+    proptest! {
+        #[test]
+        fn stickers_meet_telegram_limits(
+            layout in layouts(),
+            crop in crops(),
+            width in sides(),
+            height in sides(),
+        ) {
+            let (w, h) = layout.resize(width, height, crop).canvas;
+            if layout == LayoutProp::CustomEmoji {
+                prop_assert_eq!((w, h), (EMOJI_SIZE, EMOJI_SIZE));
+            } else {
+                prop_assert_eq!(w.max(h), STICKER_SIDE);
+                prop_assert!(w.min(h) >= 1);
+            }
+        }
+
+        #[test]
+        fn media_stays_in_bounds(
+            layout in layouts(),
+            crop in crops(),
+            width in sides(),
+            height in sides(),
+        ) {
+            let placement = layout.resize(width, height, crop);
+            let rect = placement.crop;
+            prop_assert!(rect.width >= 1 && rect.height >= 1);
+            prop_assert!(u64::from(rect.x) + u64::from(rect.width) <= u64::from(width));
+            prop_assert!(u64::from(rect.y) + u64::from(rect.height) <= u64::from(height));
+
+            let (s_width, s_height) = placement.scaled;
+            let (b_width, b_height) = placement.bounds;
+            prop_assert!(s_width <= b_width && s_height <= b_height);
+            let (x, y) = placement.offset.unwrap_or_default();
+            let (c_width, c_height) = placement.canvas;
+            prop_assert!(x + s_width <= c_width && y + s_height <= c_height);
+        }
+
+        #[test]
+        fn fit_keeps_the_aspect_ratio(
+            width in sides(),
+            height in sides(),
+            b_width in 1..=512u32,
+            b_height in 1..=512u32,
+        ) {
+            let (w, h) = fit(width, height, b_width, b_height);
+            prop_assert!(w >= 1 && h >= 1 && w <= b_width && h <= b_height);
+            // One side matches the box, and the other is as close to the
+            // exact ratio as whole pixels allow.
+            let wider = u64::from(width) * u64::from(b_height)
+                >= u64::from(height) * u64::from(b_width);
+            let (side, exact) = if wider {
+                prop_assert_eq!(w, b_width);
+                (h, f64::from(height) * f64::from(b_width) / f64::from(width))
+            } else {
+                prop_assert_eq!(h, b_height);
+                (w, f64::from(width) * f64::from(b_height) / f64::from(height))
+            };
+            prop_assert!(
+                (f64::from(side) - exact).abs() <= 0.5 + 1e-6 || (side == 1 && exact < 1.0)
+            );
+        }
+
+        #[test]
+        fn fill_leaves_no_padding(
+            layout in layouts(),
+            width in 512..=4096u32,
+            height in 512..=4096u32,
+        ) {
+            let placement = layout.resize(width, height, Crop::Fill);
+            let (c_width, c_height) = placement.canvas;
+            let (s_width, s_height) = placement.scaled;
+            // Media at least as large as the box fills it but for roundings.
+            prop_assert!(c_width - s_width <= 2 && c_height - s_height <= 2);
+        }
+    }
+}
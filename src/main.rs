@@ -1,6 +1,14 @@
+mod album;
 mod convert;
+mod effects;
 mod errors;
+mod geometry;
+mod media_info;
 mod message;
+mod queue;
+mod source;
+mod sticker_set;
+mod store;
 mod types;
 
 use message::*;
@@ -83,6 +91,26 @@ async fn run() {
     log::info!("Starting bot...");
 
     let bot = Bot::from_env().auto_send();
+    let db_path = env::var("DATABASE_PATH").unwrap_or_else(|_| "stickers.db".to_owned());
+    let store = store::Store::open(&db_path).expect("open the job store");
+    let workers = match env::var("CONVERT_WORKERS").map(|x| x.parse::<usize>()) {
+        Ok(Ok(workers)) if workers >= 1 => workers,
+        Ok(_) => {
+            log::warn!(
+                "CONVERT_WORKERS is not a positive number, using {}.",
+                queue::DEFAULT_WORKERS
+            );
+            queue::DEFAULT_WORKERS
+        }
+        Err(_) => queue::DEFAULT_WORKERS,
+    };
+    let queue = queue::Queue::new(workers);
+    // Names of sticker sets end with the username of the bot, which does not
+    // change while it runs.
+    let me = bot.get_me().await.expect("get the bot user");
+
+    let sources = source::Sources::default();
+    sources.spawn_eviction();
 
     let handler = dptree::entry()
         .branch(
@@ -93,7 +121,16 @@ async fn run() {
         .branch(Update::filter_message().endpoint(message_handler))
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
-    let mut dispatcher = Dispatcher::builder(bot.clone(), handler).build();
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![
+            store,
+            queue,
+            me,
+            queue::Latest::default(),
+            sources,
+            album::Albums::default()
+        ])
+        .build();
     dispatcher.setup_ctrlc_handler();
     if env::var("TELOXIDE_USE_WEBHOOK").is_ok() {
         dispatcher
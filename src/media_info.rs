@@ -0,0 +1,239 @@
+use crate::errors::*;
+use serde_json::Value;
+
+/// Metadata of the video stream of a file, read from the JSON output of
+/// ffprobe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaInfo {
+    pub codec: String,
+    pub pixel_format: String,
+    /// Whether the frames carry an alpha channel, either in the pixel format
+    /// or, for VP8/VP9 in WebM, in a side stream of their own.
+    pub alpha: bool,
+    /// Clockwise rotation in degrees players apply when showing the video.
+    pub rotation: i32,
+    /// Size of the frames as stored, before the rotation.
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: Option<f32>,
+    pub frame_count: Option<u64>,
+    pub duration: f32,
+}
+
+/// Reads a number, which ffprobe writes either as a JSON number or a string.
+fn number(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .filter(|x| x.is_finite())
+}
+
+/// Reads a rate like `30000/1001`, where `0/0` stands for an unknown one.
+fn rational(v: &Value) -> Option<f32> {
+    let (n, d) = v.as_str()?.split_once('/')?;
+    let (n, d): (f32, f32) = (n.parse().ok()?, d.parse().ok()?);
+    (n > 0.0 && d > 0.0).then(|| n / d)
+}
+
+fn has_alpha(pixel_format: &str) -> bool {
+    ["yuva", "gbrap", "ya"]
+        .iter()
+        .any(|p| pixel_format.starts_with(p))
+        || ["rgba", "bgra", "argb", "abgr"]
+            .iter()
+            .any(|p| pixel_format.contains(p))
+}
+
+impl MediaInfo {
+    /// Parses the output of `ffprobe -print_format json -show_streams
+    /// -show_format`. Cover art is skipped, and of several video streams the
+    /// default one is taken.
+    pub fn from_probe(json: &[u8]) -> Result<Self, ConvertError> {
+        let probe: Value = serde_json::from_slice(json).map_err(ConvertError::wrap)?;
+        let err = |name: &str, v: &Value| ConvertError::Format(name.to_owned(), v.to_string());
+
+        let streams: Vec<_> = probe["streams"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|s| s["codec_type"] == "video" && s["disposition"]["attached_pic"] != 1)
+            .collect();
+        let stream = streams
+            .iter()
+            .find(|s| s["disposition"]["default"] == 1)
+            .or_else(|| streams.first())
+            .ok_or_else(|| err("video stream", &probe["streams"]))?;
+
+        let width = stream["width"]
+            .as_u64()
+            .filter(|&x| x > 0)
+            .ok_or_else(|| err("width", &stream["width"]))? as u32;
+        let height = stream["height"]
+            .as_u64()
+            .filter(|&x| x > 0)
+            .ok_or_else(|| err("height", &stream["height"]))? as u32;
+        let codec = stream["codec_name"].as_str().unwrap_or_default().to_owned();
+        let pixel_format = stream["pix_fmt"].as_str().unwrap_or_default().to_owned();
+        let alpha = has_alpha(&pixel_format) || stream["tags"]["alpha_mode"] == "1";
+
+        // Newer ffmpeg reports a display matrix, which turns counterclockwise,
+        // while older files carry a clockwise `rotate` tag.
+        let rotation = stream["side_data_list"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|d| number(&d["rotation"]).map(|r| -r))
+            .or_else(|| number(&stream["tags"]["rotate"]))
+            .unwrap_or_default()
+            .round() as i32;
+
+        let frame_rate =
+            rational(&stream["avg_frame_rate"]).or_else(|| rational(&stream["r_frame_rate"]));
+        let frame_count = number(&stream["nb_frames"]).map(|x| x as u64);
+        let duration = number(&stream["duration"])
+            .or_else(|| number(&probe["format"]["duration"]))
+            .map(|x| x as f32)
+            .or_else(|| Some(frame_count? as f32 / frame_rate?))
+            .filter(|&x| x >= 0.0)
+            .ok_or_else(|| err("duration", &probe["format"]["duration"]))?;
+
+        Ok(Self {
+            codec,
+            pixel_format,
+            alpha,
+            rotation: rotation.rem_euclid(360),
+            width,
+            height,
+            frame_rate,
+            frame_count,
+            duration,
+        })
+    }
+
+    /// Size of the frames as shown, which is what ffmpeg outputs since it
+    /// applies the rotation while decoding.
+    pub fn display_size(&self) -> (u32, u32) {
+        match self.rotation {
+            90 | 270 => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A portrait clip from a phone, as newer ffprobe reports it.
+    const PHONE: &str = r#"{
+        "streams": [
+            {
+                "index": 0,
+                "codec_name": "h264",
+                "codec_type": "video",
+                "width": 1920,
+                "height": 1080,
+                "pix_fmt": "yuv420p",
+                "r_frame_rate": "30000/1001",
+                "avg_frame_rate": "30000/1001",
+                "duration": "3.003000",
+                "nb_frames": "90",
+                "disposition": {"default": 1, "attached_pic": 0},
+                "side_data_list": [
+                    {
+                        "side_data_type": "Display Matrix",
+                        "displaymatrix": "\n00000000:            0       65536           0\n00000001:       -65536           0           0\n00000002:            0           0  1073741824\n",
+                        "rotation": -90
+                    }
+                ]
+            },
+            {
+                "index": 1,
+                "codec_name": "aac",
+                "codec_type": "audio",
+                "disposition": {"default": 1, "attached_pic": 0}
+            }
+        ],
+        "format": {"format_name": "mov,mp4,m4a,3gp,3g2,mj2", "duration": "3.015000"}
+    }"#;
+
+    /// A video sticker, where WebM keeps the duration in the format only and
+    /// VP9 marks its alpha in a tag.
+    const STICKER: &str = r#"{
+        "streams": [
+            {
+                "index": 0,
+                "codec_name": "vp9",
+                "codec_type": "video",
+                "width": 512,
+                "height": 512,
+                "pix_fmt": "yuv420p",
+                "r_frame_rate": "30/1",
+                "avg_frame_rate": "0/0",
+                "disposition": {"default": 1, "attached_pic": 0},
+                "tags": {"alpha_mode": "1", "ENCODER": "Lavc59.37.100 libvpx-vp9"}
+            }
+        ],
+        "format": {"format_name": "matroska,webm", "duration": "2.960000"}
+    }"#;
+
+    fn probe(json: &str) -> MediaInfo {
+        MediaInfo::from_probe(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn display_matrix_turns_counterclockwise() {
+        let info = probe(PHONE);
+        assert_eq!(info.rotation, 90);
+        assert_eq!(info.display_size(), (1080, 1920));
+
+        let json = PHONE.replace(r#""rotation": -90"#, r#""rotation": 90"#);
+        assert_eq!(probe(&json).rotation, 270);
+    }
+
+    #[test]
+    fn rotate_tag_turns_clockwise() {
+        let json = PHONE
+            .replace(r#""rotation": -90"#, r#""rotation_unused": 0"#)
+            .replace(
+                r#""disposition": {"default": 1, "attached_pic": 0},
+                "side_data_list""#,
+                r#""disposition": {"default": 1, "attached_pic": 0},
+                "tags": {"rotate": "90"},
+                "side_data_list""#,
+            );
+        let info = probe(&json);
+        assert_eq!(info.rotation, 90);
+        assert_eq!(info.display_size(), (1080, 1920));
+    }
+
+    #[test]
+    fn alpha_mode_tag_marks_alpha() {
+        assert!(probe(STICKER).alpha);
+        assert!(!probe(PHONE).alpha);
+
+        let json = STICKER.replace(r#""alpha_mode": "1""#, r#""alpha_mode": "0""#);
+        assert!(!probe(&json).alpha);
+    }
+
+    #[test]
+    fn frame_rate_is_a_fraction() {
+        let rate = probe(PHONE).frame_rate.unwrap();
+        assert!((rate - 29.97).abs() < 0.01, "{}", rate);
+        assert_eq!(probe(PHONE).frame_count, Some(90));
+
+        // An unknown average rate falls back to the base one.
+        assert_eq!(probe(STICKER).frame_rate, Some(30.0));
+    }
+
+    #[test]
+    fn duration_falls_back_to_the_format() {
+        assert_eq!(probe(PHONE).duration, 3.003);
+        assert_eq!(probe(STICKER).duration, 2.96);
+
+        let json = STICKER.replace(r#""duration": "2.960000""#, r#""bit_rate": "0""#);
+        assert!(MediaInfo::from_probe(json.as_bytes()).is_err());
+    }
+}
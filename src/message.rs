@@ -1,4 +1,13 @@
-use crate::{convert::*, errors::*, types::*};
+use crate::{
+    album::Albums,
+    convert::*,
+    errors::*,
+    queue::{Latest, Queue},
+    source::Sources,
+    sticker_set::*,
+    store::{AlbumItem, ResultKey, Store},
+    types::*,
+};
 use teloxide::{
     adaptors::AutoSend,
     payloads::{
@@ -7,123 +16,713 @@ use teloxide::{
     },
     prelude2::*,
     types::{
-        CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia,
-        InputMediaDocument, Message, True,
+        CallbackQuery, InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup,
+        InputFile, InputMedia, InputMediaDocument, Me, Message, True,
     },
     Bot, RequestError,
 };
+use ubyte::ToByteUnit;
 
-fn get_props(m: &Message) -> (Option<LayoutProp>, Option<PlaybackProp>) {
+/// Reads the props from the caption of messages sent before the state moved
+/// into callback data.
+fn get_legacy_state(m: &Message) -> Option<State> {
     let caption = m.caption().unwrap_or("");
     let arr: Vec<_> = caption.split(';').collect();
-    let layout: Option<LayoutProp> = arr.get(0).and_then(|s| s.parse().ok());
+    let layout: LayoutProp = arr.first().and_then(|s| s.parse().ok())?;
     let playback: Option<PlaybackProp> = arr.get(1).and_then(|s| s.parse().ok());
-    (layout, playback)
+    let emoji: EmojiProp = arr.get(2).and_then(|s| s.parse().ok()).unwrap_or_default();
+    Some(State {
+        layout,
+        playback,
+        emoji,
+        effects: Effects::default(),
+    })
 }
 
-fn make_caption(layout: LayoutProp, playback: Option<PlaybackProp>) -> String {
-    log::debug!("make_caption: {:?}, {:?}", layout, playback);
-    format!(
-        "{};{}",
-        layout,
-        playback.map(|o| o.to_string()).unwrap_or_default()
-    )
+fn get_state(m: &Message) -> Option<State> {
+    m.reply_markup()
+        .into_iter()
+        .flat_map(|markup| markup.inline_keyboard.iter().flatten())
+        .find_map(|button| match &button.kind {
+            InlineKeyboardButtonKind::CallbackData(data) => {
+                data.parse::<CallbackData>().ok()?.state
+            }
+            _ => None,
+        })
+        .or_else(|| get_legacy_state(m))
+}
+
+/// Fills in the emoji of the sticker `m`, which are kept in the store. States
+/// of buttons sent before that carry them, and they are moved over.
+async fn load_emoji(store: &Store, m: &Message, state: &mut State) -> rusqlite::Result<()> {
+    if state.emoji.is_empty() {
+        state.emoji = store.emoji(m.chat_id(), m.id).await?.unwrap_or_default();
+        Ok(())
+    } else {
+        store.set_emoji(m.chat_id(), m.id, &state.emoji).await
+    }
+}
+
+/// Reads back from the caption whether the sticker is translucent, which is
+/// kept nowhere else.
+fn get_translucent(m: &Message) -> Option<bool> {
+    m.caption()?.lines().find_map(|line| match line {
+        "Translucent: yes" => Some(true),
+        "Translucent: no" => Some(false),
+        _ => None,
+    })
+}
+
+fn make_caption(state: &State, size: Option<u64>, translucent: Option<bool>) -> String {
+    log::debug!("make_caption: {:?}, {:?}, {:?}", state, size, translucent);
+    let mut lines = vec![match state.layout {
+        LayoutProp::Small(p, v) => format!("Size: Small, {}, {}", p, v),
+        LayoutProp::Medium(p, v) => format!("Size: Medium, {}, {}", p, v),
+        LayoutProp::Height(height, p, v) => format!("Size: {}px high, {}, {}", height, p, v),
+        LayoutProp::Large => "Size: Large".to_owned(),
+        LayoutProp::CustomEmoji => "Size: Custom emoji".to_owned(),
+    }];
+    if let Some(playback) = state.playback {
+        lines.push(match playback.trim {
+            Some(Trim::At(t)) => format!("Clip: 3s from {:.1}s", t),
+            Some(trim) => format!("Clip: 3s from the {}", trim),
+            None if playback.speed_up => "Speed: sped up to 3s".to_owned(),
+            None => "Speed: original".to_owned(),
+        });
+    }
+    match state.effects.crop {
+        Crop::Fit => {}
+        crop @ Crop::Manual { .. } => {
+            lines.push(format!("Crop: {}, {}x", crop.text(), crop.zoom()))
+        }
+        crop => lines.push(format!("Crop: {}", crop.text())),
+    }
+    if let Some(key) = state.effects.key {
+        let color = match key.color {
+            Some([r, g, b]) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+            None => "corner colour".to_owned(),
+        };
+        lines.push(format!(
+            "Background: {} removed, {}% tolerance",
+            color, key.tolerance
+        ));
+    }
+    if let Some(outline) = state.effects.outline {
+        lines.push(format!(
+            "Outline: {}, {}px",
+            outline.color.text(),
+            outline.width
+        ));
+    }
+    if let Some(text) = &state.effects.text {
+        lines.push(format!(
+            "Text: {}, {}",
+            text.position.text(),
+            text.style.text()
+        ));
+    }
+    if !state.emoji.is_empty() {
+        lines.push(format!("Emoji: {}", state.emoji));
+    }
+    if let Some(size) = size {
+        lines.push(format!("File: {:.1}", size.bytes()));
+    }
+    if let Some(translucent) = translucent {
+        lines.push(format!(
+            "Translucent: {}",
+            if translucent { "yes" } else { "no" }
+        ));
+    }
+    lines.join("\n")
+}
+
+fn file_size(m: &Message) -> Option<u64> {
+    m.document().and_then(|d| d.file_size).map(u64::from)
+}
+
+fn make_button(state: &State, callback: Callback) -> InlineKeyboardButton {
+    CallbackData {
+        callback,
+        state: Some(state.clone()),
+    }
+    .into()
 }
 
-fn make_layout_keyboard(layout: LayoutProp) -> InlineKeyboardMarkup {
-    log::debug!("make_layout_keyboard: {:?}", layout);
+/// The rows of buttons that change how the media is converted.
+fn make_layout_rows(
+    state: &State,
+    media_type: Option<MediaType>,
+) -> Vec<Vec<InlineKeyboardButton>> {
     use Callback::*;
 
-    fn make_buttons(set: &[Callback], cur: Callback) -> Vec<InlineKeyboardButton> {
+    let make_button = |callback: Callback| make_button(state, callback);
+    let make_buttons = |set: &[Callback], cur: Callback| -> Vec<InlineKeyboardButton> {
         set.iter()
-            .filter_map(|&x| if x != cur { Some(x.into()) } else { None })
+            .filter(|&&x| x != cur)
+            .map(|&x| make_button(x))
             .collect()
-    }
+    };
 
-    let size_callback = [Small, Medium, Large];
+    let size_callback = [Small, Medium, Large, CustomEmoji];
     let position_callback = [Left, Center, Right];
+    let vertical_callback = [Top, Middle, Bottom];
+    let time_callback = [SpeedUp, TrimStart, TrimMiddle, TrimEnd];
 
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
 
-    match layout {
-        LayoutProp::Small(p) => {
-            keyboard.push(make_buttons(&size_callback, Small));
-            keyboard.push(make_buttons(&position_callback, p.into()));
-        }
-        LayoutProp::Medium(p) => {
-            keyboard.push(make_buttons(&size_callback, Medium));
+    let boxed = match state.layout {
+        LayoutProp::Small(p, v) => Some((Small, 128, p, v)),
+        LayoutProp::Medium(p, v) => Some((Medium, 256, p, v)),
+        // No size button is current for a free height.
+        LayoutProp::Height(height, p, v) => Some((Shorter, height, p, v)),
+        LayoutProp::Large | LayoutProp::CustomEmoji => None,
+    };
+    match (state.layout, boxed) {
+        // Lottie animations are always 512px*512px.
+        _ if media_type == Some(MediaType::Animated) => (),
+        (_, Some((cur, height, p, v))) => {
+            keyboard.push(make_buttons(&size_callback, cur));
+            keyboard.push(
+                [(Shorter, height > 32), (Taller, height < 512)]
+                    .into_iter()
+                    .filter(|&(_, shown)| shown)
+                    .map(|(x, _)| make_button(x))
+                    .collect(),
+            );
             keyboard.push(make_buttons(&position_callback, p.into()));
+            keyboard.push(make_buttons(&vertical_callback, v.into()));
         }
-        LayoutProp::Large => {
+        (LayoutProp::Large, _) => {
             keyboard.push(make_buttons(&size_callback, Large));
         }
+        _ => {
+            keyboard.push(make_buttons(&size_callback, CustomEmoji));
+        }
+    }
+    if let Some(playback) = state.playback.filter(|p| p.speed_up || p.trim.is_some()) {
+        let cur = match playback.trim {
+            Some(Trim::Start) => Some(TrimStart),
+            Some(Trim::Middle) => Some(TrimMiddle),
+            Some(Trim::End) => Some(TrimEnd),
+            Some(Trim::At(_)) => None,
+            None => Some(SpeedUp),
+        };
+        keyboard.push(
+            time_callback
+                .iter()
+                .filter(|&&x| Some(x) != cur)
+                .map(|&x| make_button(x))
+                .collect(),
+        );
     }
+    keyboard
+}
 
+fn make_keyboard(state: &State, media_type: Option<MediaType>) -> InlineKeyboardMarkup {
+    log::debug!("make_keyboard: {:?}, {:?}", state, media_type);
+    let mut keyboard = make_layout_rows(state, media_type);
+    if media_type != Some(MediaType::Animated) {
+        keyboard.push(vec![make_button(state, Callback::NextCrop)]);
+        if let Crop::Manual { .. } = state.effects.crop {
+            keyboard.push(
+                [
+                    Callback::NudgeLeft,
+                    Callback::NudgeUp,
+                    Callback::NudgeDown,
+                    Callback::NudgeRight,
+                ]
+                .map(|c| make_button(state, c))
+                .to_vec(),
+            );
+            keyboard.push(
+                [Callback::ZoomOut, Callback::ZoomIn]
+                    .map(|c| make_button(state, c))
+                    .to_vec(),
+            );
+        }
+        let mut row = vec![make_button(state, Callback::RemoveBackground)];
+        if state.effects.key.is_some() {
+            row.extend(
+                [Callback::ToleranceDown, Callback::ToleranceUp].map(|c| make_button(state, c)),
+            );
+        }
+        keyboard.push(row);
+        let mut row = vec![make_button(state, Callback::ToggleOutline)];
+        if state.effects.outline.is_some() {
+            row.extend(
+                [
+                    Callback::NextOutlineColor,
+                    Callback::Thinner,
+                    Callback::Thicker,
+                ]
+                .map(|c| make_button(state, c)),
+            );
+        }
+        keyboard.push(row);
+        if state.effects.text.is_some() {
+            keyboard.push(
+                [
+                    Callback::RemoveText,
+                    Callback::MoveText,
+                    Callback::NextTextStyle,
+                ]
+                .map(|c| make_button(state, c))
+                .to_vec(),
+            );
+        }
+    }
+    keyboard.push(
+        SUGGESTED_EMOJI
+            .iter()
+            .map(|&e| make_button(state, Callback::Emoji(e)))
+            .collect(),
+    );
     InlineKeyboardMarkup::new(keyboard)
 }
 
+/// Album summaries only offer layout changes, which apply to every item.
+fn make_album_keyboard(state: &State) -> InlineKeyboardMarkup {
+    log::debug!("make_album_keyboard: {:?}", state);
+    InlineKeyboardMarkup::new(make_layout_rows(state, None))
+}
+
+fn get_media(m: &Message) -> Option<(&str, MediaType)> {
+    if let Some(doc) = m.document() {
+        let mime = doc.mime_type.clone();
+        let mime_type = mime.as_ref().map(|x| x.type_());
+        let extension = doc
+            .file_name
+            .as_deref()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_lowercase());
+        if matches!(extension.as_deref(), Some("json" | "tgs")) {
+            Some((&doc.file_id, MediaType::Animated))
+        } else if mime == Some(mime::IMAGE_GIF) || mime_type == Some(mime::VIDEO) {
+            Some((&doc.file_id, MediaType::Video))
+        } else if mime_type == Some(mime::IMAGE) {
+            Some((&doc.file_id, MediaType::Image))
+        } else {
+            None
+        }
+    } else if let Some(img) = m.photo().and_then(|x| x.last()) {
+        Some((&img.file_id, MediaType::Image))
+    } else if let Some(vid) = m.video() {
+        Some((&vid.file_id, MediaType::Video))
+    } else {
+        m.animation()
+            .map(|anim| (anim.file_id.as_str(), MediaType::Video))
+    }
+}
+
+fn get_sticker(m: &Message) -> Option<(&str, MediaType)> {
+    m.sticker().map(|s| {
+        let media_type = match (s.is_animated, s.is_video) {
+            (true, _) => MediaType::Animated,
+            (_, true) => MediaType::Video,
+            _ => MediaType::Image,
+        };
+        (s.file_id.as_str(), media_type)
+    })
+}
+
+fn get_archive(m: &Message) -> Option<&str> {
+    let doc = m.document()?;
+    let is_zip = doc.mime_type.as_ref().map(|x| x.subtype().as_str()) == Some("zip")
+        || doc
+            .file_name
+            .as_deref()
+            .is_some_and(|name| name.to_lowercase().ends_with(".zip"));
+    is_zip.then_some(doc.file_id.as_str())
+}
+
+fn media_type(m: &Message) -> Option<MediaType> {
+    get_media(m).map(|(_, media_type)| media_type)
+}
+
+/// How a conversion was recorded, to complete once its result is sent.
+struct Job {
+    id: Option<i64>,
+    /// Where to cache the result, unless it has effects, which are not
+    /// cached.
+    key: Option<ResultKey>,
+}
+
+/// A converted sticker along with its props and the recorded job.
+type Converted = (Sticker, Job);
+
+async fn get_settings(store: &Store, user_id: Option<i64>) -> Settings {
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => return Settings::default(),
+    };
+    match store.settings(user_id).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Failed to load the settings: {}", e);
+            Settings::default()
+        }
+    }
+}
+
+/// Returns the result sent before for the same props, if there is one.
+async fn get_cached(store: &Store, key: &ResultKey) -> Option<Sticker> {
+    match store.cached_result(key).await {
+        Ok(Some((result_file_id, size))) => {
+            log::debug!("send the cached result for {:?}", key);
+            Some(Sticker {
+                file: InputFile::file_id(result_file_id),
+                size,
+                layout: key.layout,
+                playback: key.playback,
+                translucent: None,
+            })
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("Failed to look up the result cache: {}", e);
+            None
+        }
+    }
+}
+
+/// Converts a file with the user's settings and records it as a job. A file
+/// converted with the same props before is answered with the earlier result.
+#[allow(clippy::too_many_arguments)]
+async fn convert_file(
+    bot: &AutoSend<Bot>,
+    store: &Store,
+    sources: &Sources,
+    user_id: Option<i64>,
+    chat_id: i64,
+    file_id: &str,
+    media_type: MediaType,
+    layout: Option<LayoutProp>,
+    playback: Option<PlaybackProp>,
+    effects: &Effects,
+) -> Result<Converted, ConvertError> {
+    let settings = get_settings(store, user_id).await;
+    let id = store
+        .start_job(user_id, chat_id, file_id, media_type)
+        .await
+        .map_err(|e| log::error!("Failed to record the job: {}", e))
+        .ok();
+    let r = async {
+        let source = sources.get(bot, file_id).await?;
+        let encoder = encoder(media_type, settings);
+        let cached = match layout {
+            Some(_) if !effects.is_empty() => None,
+            // Videos without a playback yet take it from the settings.
+            Some(layout) if media_type != MediaType::Video || playback.is_some() => {
+                get_cached(
+                    store,
+                    &ResultKey {
+                        source_unique_id: source.unique_id().to_owned(),
+                        layout,
+                        playback,
+                        encoder: encoder.clone(),
+                    },
+                )
+                .await
+            }
+            _ => None,
+        };
+        let sticker = match cached {
+            Some(sticker) => sticker,
+            None => {
+                convert(
+                    bot, &source, media_type, layout, playback, effects, settings,
+                )
+                .await?
+            }
+        };
+        let key = effects.is_empty().then(|| ResultKey {
+            source_unique_id: source.unique_id().to_owned(),
+            layout: sticker.layout,
+            playback: sticker.playback,
+            encoder,
+        });
+        Ok::<_, ConvertError>((sticker, key))
+    }
+    .await;
+    if let Some(id) = id {
+        let result = r.as_ref().map(|(s, _)| (s.layout, s.playback));
+        if let Err(e) = store.finish_job(id, result).await {
+            log::error!("Failed to record the job: {}", e);
+        }
+    }
+    r.map(|(sticker, key)| (sticker, Job { id, key }))
+}
+
 async fn convert_message(
     m: &Message,
     bot: &AutoSend<Bot>,
+    store: &Store,
+    sources: &Sources,
     layout: Option<LayoutProp>,
     playback: Option<PlaybackProp>,
-) -> Result<(InputFile, LayoutProp, Option<PlaybackProp>), ConvertError> {
-    let media = {
-        if let Some(doc) = m.document() {
-            let mime = doc.mime_type.clone();
-            let mime_type = mime.as_ref().map(|x| x.type_());
-            if mime == Some(mime::IMAGE_GIF) || mime_type == Some(mime::VIDEO) {
-                Some((&doc.file_id, MediaType::Video))
-            } else if mime_type == Some(mime::IMAGE) {
-                Some((&doc.file_id, MediaType::Image))
-            } else {
-                None
-            }
-        } else if let Some(img) = m.photo().map(|x| x.last()).flatten() {
-            Some((&img.file_id, MediaType::Image))
-        } else if let Some(vid) = m.video() {
-            Some((&vid.file_id, MediaType::Video))
-        } else if let Some(anim) = m.animation() {
-            Some((&anim.file_id, MediaType::Video))
-        } else {
-            None
+    effects: &Effects,
+) -> Result<Converted, ConvertError> {
+    let media = get_media(m);
+    log::debug!("convert {:?}...", media);
+    let (file_id, media_type) = media.ok_or(ConvertError::MediaType)?;
+    let user_id = m.from().map(|u| u.id);
+    convert_file(
+        bot,
+        store,
+        sources,
+        user_id,
+        m.chat_id(),
+        file_id,
+        media_type,
+        layout,
+        playback,
+        effects,
+    )
+    .await
+}
+
+/// A reply telling the user their place in the queue. It is removed when
+/// dropped, including when the conversion is cancelled.
+struct QueueStatus {
+    bot: AutoSend<Bot>,
+    message: Option<Message>,
+}
+
+impl Drop for QueueStatus {
+    fn drop(&mut self) {
+        if let Some(s) = self.message.take() {
+            let bot = self.bot.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bot.delete_message(s.chat_id(), s.id).await {
+                    log::error!("Failed to delete the queue status: {}", e);
+                }
+            });
         }
+    }
+}
+
+/// Runs a conversion once the queue has a worker for it. While it waits, a
+/// reply to `m` tells the user their place in the queue; the reply is removed
+/// once the conversion is over.
+async fn queued<T>(
+    bot: &AutoSend<Bot>,
+    queue: &Queue,
+    user_id: Option<i64>,
+    m: &Message,
+    f: impl std::future::Future<Output = T>,
+) -> T {
+    let mut ticket = queue.join(user_id);
+    let mut status = QueueStatus {
+        bot: bot.clone(),
+        message: None,
     };
-    log::debug!("convert {:?}...", media);
-    if let Some((file_id, media_type)) = media {
-        convert(bot, file_id, media_type, layout, playback).await
-    } else {
-        Err(ConvertError::MediaType)
+    while ticket.position() > 0 {
+        let text = format!("You are #{} in queue.", ticket.position());
+        let r = match &status.message {
+            Some(s) => bot.edit_message_text(s.chat_id(), s.id, text).await,
+            None => {
+                bot.send_message(m.chat_id(), text)
+                    .reply_to_message_id(m.id)
+                    .await
+            }
+        };
+        match r {
+            Ok(s) => status.message = Some(s),
+            Err(e) => log::error!("Failed to update the queue status: {}", e),
+        }
+        if !ticket.changed().await {
+            break;
+        }
+    }
+    if let Some(s) = &status.message {
+        if let Err(e) = bot
+            .edit_message_text(s.chat_id(), s.id, "Converting…")
+            .await
+        {
+            log::error!("Failed to update the queue status: {}", e);
+        }
     }
+
+    let r = f.await;
+    drop(ticket);
+    r
+}
+
+async fn record_result(store: &Store, job: Job, m: &Message) {
+    let doc = match m.document() {
+        Some(doc) => doc,
+        None => return,
+    };
+    if let Some(id) = job.id {
+        if let Err(e) = store.set_result(id, &doc.file_id).await {
+            log::error!("Failed to record the job: {}", e);
+        }
+    }
+    let size = file_size(m).unwrap_or_default();
+    if let Some(key) = job.key {
+        if let Err(e) = store.cache_result(&key, &doc.file_id, size).await {
+            log::error!("Failed to cache the result: {}", e);
+        }
+    }
+}
+
+async fn reply_converted(
+    bot: &AutoSend<Bot>,
+    store: &Store,
+    m_origin: &Message,
+    (sticker, job): Converted,
+) -> Result<(), RequestError> {
+    let state = State {
+        layout: sticker.layout,
+        playback: sticker.playback,
+        emoji: EmojiProp::default(),
+        effects: Effects::default(),
+    };
+    let sent = bot
+        .send_document(m_origin.chat_id(), sticker.file)
+        .caption(make_caption(
+            &state,
+            Some(sticker.size),
+            sticker.translucent,
+        ))
+        .reply_to_message_id(m_origin.id)
+        .reply_markup(make_keyboard(&state, media_type(m_origin)))
+        .await?;
+    record_result(store, job, &sent).await;
+    Ok(())
+}
+
+fn make_settings_keyboard(settings: &Settings) -> InlineKeyboardMarkup {
+    log::debug!("make_settings_keyboard: {:?}", settings);
+    use Setting::{Format, Playback, Size};
+
+    let keyboard = [
+        vec![
+            Size(SizePref::Auto),
+            Size(SizePref::Small),
+            Size(SizePref::Medium),
+            Size(SizePref::Large),
+        ],
+        vec![
+            Setting::Alignment(Alignment::Left),
+            Setting::Alignment(Alignment::Center),
+            Setting::Alignment(Alignment::Right),
+        ],
+        vec![
+            Playback(PlaybackPolicy::Ask),
+            Playback(PlaybackPolicy::SpeedUp),
+        ],
+        vec![Format(OutputFormat::Png), Format(OutputFormat::WebP)],
+    ]
+    .into_iter()
+    .map(|row| {
+        row.into_iter()
+            .map(|setting| {
+                let button: InlineKeyboardButton =
+                    CallbackData::from(Callback::Setting(setting)).into();
+                if settings.contains(setting) {
+                    button.text(format!("✅ {}", setting.text()))
+                } else {
+                    button
+                }
+            })
+            .collect::<Vec<_>>()
+    });
+
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+fn make_export_keyboard(media_type: MediaType, format: ExportFormat) -> InlineKeyboardMarkup {
+    log::debug!("make_export_keyboard: {:?}, {:?}", media_type, format);
+    let buttons: Vec<InlineKeyboardButton> = ExportFormat::choices(media_type)
+        .iter()
+        .filter(|&&x| x != format)
+        .map(|&x| CallbackData::from(Callback::Export(x)).into())
+        .collect();
+    InlineKeyboardMarkup::new([buttons])
 }
 
 pub async fn command_handler(
     m: Message,
     bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    me: Me,
     cmd: Command,
 ) -> Result<(), RequestError> {
     let text = match cmd {
         Command::Start => r#"Welcome\! Please send me an image or a video clip\."#,
         Command::Help => {
             r#"Send me an image or a video clip and I will convert it into the format required by @Stickers\.
+Send an album to convert all of its items at once; the buttons under the summary change all of them together\.
+Send a ZIP archive of images and clips to convert all of them with your /settings; you will get a ZIP of stickers and a report of the files that failed\.
+Send me a sticker and I will export it, a static one as a PNG image, a video one as an MP4 or GIF clip and an animated one as its Lottie JSON\.
+Lottie animations sent as a \.json document are checked against the limits of animated stickers and packed into a \.tgs file\.
 On successful convertion, you may forward the replied document to @Stickers to make your sticker set, or click on the buttons to change the conversion style:
 
 \- Sticker Size
   *Small* the converted sticker will fit in a box of 512px\*128px and add transparent paddings
   *Medium* the converted sticker will fit in a box of 512px\*256px and add transparent paddings
   *Large* the converted sticker will fit in a box of 512px\*512px
-\- Sticker Positioning \(for small and medium sized stickers\)
+  *Emoji* the converted sticker will be a custom emoji of exactly 100px\*100px with transparent paddings
+  *Height −* and *Height \+* make the box of a small or medium sticker 32px shorter or taller, up to 512px
+\- Sticker Positioning \(for small, medium and other heights\)
   *Left* place the sticker on the left
   *Center* place the sticker in the middle
   *Rignt* place the sticker on the right
+  *Top*, *Middle* and *Bottom* place a sticker wider than its box at the top, in the middle or at the bottom
+
+Reply to a converted sticker with one of the commands below to put it into a sticker set owned by you:
+  */newpack* _name_ _title_ create a new sticker set with the sticker
+  */addto* _name_ add the sticker to an existing sticker set
+The set type \(static or video\) follows the sticker, and the name gets the required "\_by\_bot" suffix automatically\.
+
+Send */export* _name_ to download a whole sticker set as a ZIP archive with a manifest of its emoji\. Add _png_, _gif_, _mp4_ or _json_ to convert the stickers as well\.
+
+\- Long Video Clips \(over 3s\)
+  *Speed me up\!* compress the whole clip into 3s
+  *Trim* keep 3s from the start, the middle or the end of the clip, or reply to the clip with the time to start from, e\.g\. _0:12_
+
+\- Default Settings
+  Use */settings* to choose the size, positioning, handling of long clips and image format applied to new conversions\.
+
+\- Crop \(for images and video clips\)
+  *Crop* cycle through fitting the whole media, filling the box, cutting a square out of the middle and a manual crop; with a manual crop, use the arrows to move it and *Zoom −* or *Zoom \+* to show more or less
+
+\- Background Removal \(for images and video clips\)
+  *Remove background* make the colour in the corners transparent, and use *Tolerance −* and *Tolerance \+* to remove fewer or more similar colours
+  Reply to a converted sticker with a colour such as _\#00ff00_ to remove that one instead
+
+\- Outline \(for images and video clips\)
+  *Add outline* draw a border around the sticker like on a die\-cut sticker; tap *Colour* to cycle through white, black, yellow and blue, and *Thinner* or *Thicker* to change its width
 
-Notes on translucent GIF:
-Telegram will re\-encode all GIFs you send to *mpeg4* which does not have an alpha channel even if you send the GIF "without compression", and thus the bot could never get the original GIF\. If you need translucent video stickers, consider converting the GIF to *WebM* format with online tools and resizing the video clip using this bot\.
+\- Text
+  Reply to a converted sticker with some text to draw it over the sticker, then use *Move text up* or *Move text down* to place it and *Style* to cycle through meme, subtitle and dark lettering\. Changing the size keeps the text\.
+
+\- Sticker Emoji
+  Tap the suggested emoji under a converted sticker to toggle them, or reply to the sticker with nothing but your own emoji separated by spaces\. They are used when the sticker is added to a set\.
+
+Notes on translucency:
+WebM clips with an alpha channel and animated PNGs sent as files keep their transparency; the caption tells whether the sticker is translucent\.
+Telegram will re\-encode all GIFs you send to *mpeg4* which does not have an alpha channel even if you send the GIF "without compression", and thus the bot could never get the original GIF\. If you need translucent video stickers, consider converting the GIF to *WebM* or *APNG* format with online tools and resizing the video clip using this bot\.
 Refer to: [GIF Revolution](https://telegram.org/blog/gif-revolution)
 
 Maintainer: @ferricion
 Github Repository: [telegram\-sticker\-bot](https://github.com/FerricIon/telegram-sticker-bot)"#
         }
+        Command::NewPack(name, title) => {
+            return sticker_set_handler(m, bot, store, me, name, Some(title)).await
+        }
+        Command::AddTo(name) => return sticker_set_handler(m, bot, store, me, name, None).await,
+        Command::Settings => return settings_handler(m, bot, store).await,
+        Command::Export(name, format) => {
+            tokio::spawn(async move {
+                if let Err(e) = export_set_handler(m, bot, queue, name, format).await {
+                    log::error!("{}", e);
+                }
+            });
+            return Ok(());
+        }
     };
 
     bot.send_message(m.chat_id(), text)
@@ -134,48 +733,810 @@ Github Repository: [telegram\-sticker\-bot](https://github.com/FerricIon/telegra
     Ok(())
 }
 
-pub async fn message_handler(m: Message, bot: AutoSend<Bot>) -> Result<(), RequestError> {
-    match convert_message(&m, &bot, None, None).await {
-        Ok((document, layout, playback)) => {
+async fn settings_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+) -> Result<(), RequestError> {
+    let settings = get_settings(&store, m.from().map(|u| u.id)).await;
+    bot.send_message(
+        m.chat_id(),
+        "Defaults for new conversions (size, alignment, long clips, image format):",
+    )
+    .reply_markup(make_settings_keyboard(&settings))
+    .await?;
+
+    Ok(())
+}
+
+async fn sticker_set_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    me: Me,
+    name: String,
+    title: Option<String>,
+) -> Result<(), RequestError> {
+    let r = async {
+        let user = m.from().ok_or(StickerSetError::User)?;
+        let m_sticker = m.reply_to_message().ok_or(StickerSetError::Reply)?;
+        let (file_id, media_type) = get_media(m_sticker).ok_or(StickerSetError::Reply)?;
+        let emoji = match get_state(m_sticker) {
+            Some(mut state) => {
+                load_emoji(&store, m_sticker, &mut state).await?;
+                state.emoji
+            }
+            None => EmojiProp::default(),
+        };
+        let name = full_name(&me, name.trim());
+        match title {
+            Some(title) => {
+                create_sticker_set(&bot, user.id, name, &title, file_id, media_type, &emoji).await
+            }
+            None => add_to_sticker_set(&bot, user.id, name, file_id, media_type, &emoji).await,
+        }
+    }
+    .await;
+
+    let text = match r {
+        Ok(name) => format!("Done! https://t.me/addstickers/{}", name),
+        Err(e) => {
+            log::error!("{}", e);
+            e.to_string()
+        }
+    };
+    bot.send_message(m.chat_id(), text)
+        .reply_to_message_id(m.id)
+        .await?;
+
+    Ok(())
+}
+
+async fn export_set_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    queue: Queue,
+    name: String,
+    format: Option<ExportFormat>,
+) -> Result<(), RequestError> {
+    let user_id = m.from().map(|u| u.id);
+    let r = queued(
+        &bot,
+        &queue,
+        user_id,
+        &m,
+        export_sticker_set(&bot, &name, format),
+    )
+    .await;
+    match r {
+        Ok(document) => {
+            bot.send_document(m.chat_id(), document)
+                .reply_to_message_id(m.id)
+                .await?;
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            bot.send_message(m.chat_id(), e.to_string())
+                .reply_to_message_id(m.id)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn emoji_reply_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    m_sticker: Message,
+) -> Result<(), RequestError> {
+    let r = async {
+        let state = get_state(&m_sticker).ok_or(PropsError::Message)?;
+        let emoji = m
+            .text()
+            .unwrap_or_default()
+            .parse::<EmojiProp>()?
+            .checked()?;
+        store
+            .set_emoji(m_sticker.chat_id(), m_sticker.id, &emoji)
+            .await?;
+        anyhow::Ok(State { emoji, ..state })
+    }
+    .await;
+
+    match r {
+        Ok(state) => {
+            bot.edit_message_caption(m.chat_id(), m_sticker.id)
+                .caption(make_caption(
+                    &state,
+                    file_size(&m_sticker),
+                    get_translucent(&m_sticker),
+                ))
+                .reply_markup(make_keyboard(&state, media_type(&m_sticker)))
+                .await?;
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            bot.send_message(m.chat_id(), e.to_string())
+                .reply_to_message_id(m.id)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the colour replied with from the background of the sticker.
+async fn color_reply_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    m_sticker: Message,
+) -> Result<(), RequestError> {
+    let r = async {
+        let state = get_state(&m_sticker).ok_or(PropsError::Message)?;
+        anyhow::ensure!(
+            media_type(&m_sticker) != Some(MediaType::Animated),
+            ConvertError::MediaType
+        );
+        let color = parse_color(m.text().unwrap_or_default())?;
+        let tolerance = state.effects.key.map_or(DEFAULT_TOLERANCE, |k| k.tolerance);
+        let effects = Effects {
+            key: Some(ChromaKey {
+                color: Some(color),
+                tolerance,
+            }),
+            ..state.effects
+        };
+        let state = State { effects, ..state };
+        let user_id = m.from().map(|u| u.id);
+        rerender(
+            &bot, &store, &queue, &sources, &latest, user_id, &m_sticker, state,
+        )
+        .await
+    }
+    .await;
+
+    if let Err(e) = r {
+        log::error!("{}", e);
+        bot.send_message(m.chat_id(), e.to_string())
+            .reply_to_message_id(m.id)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Draws the text replied with over the sticker.
+async fn text_reply_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    m_sticker: Message,
+) -> Result<(), RequestError> {
+    let r = async {
+        let state = get_state(&m_sticker).ok_or(PropsError::Message)?;
+        anyhow::ensure!(
+            media_type(&m_sticker) != Some(MediaType::Animated),
+            ConvertError::MediaType
+        );
+        let text = m.text().unwrap_or_default().trim().to_owned();
+        let length = text.chars().count();
+        anyhow::ensure!(
+            length <= MAX_TEXT_LENGTH,
+            PropsError::TextLength(length, MAX_TEXT_LENGTH)
+        );
+        store
+            .set_overlay_text(m_sticker.chat_id(), m_sticker.id, &text)
+            .await?;
+        let overlay = TextOverlay {
+            text,
+            ..state.effects.text.clone().unwrap_or_default()
+        };
+        let effects = Effects {
+            text: Some(overlay),
+            ..state.effects
+        };
+        let state = State { effects, ..state };
+        let user_id = m.from().map(|u| u.id);
+        rerender(
+            &bot, &store, &queue, &sources, &latest, user_id, &m_sticker, state,
+        )
+        .await
+    }
+    .await;
+
+    if let Err(e) = r {
+        log::error!("{}", e);
+        bot.send_message(m.chat_id(), e.to_string())
+            .reply_to_message_id(m.id)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn reply_error(
+    bot: &AutoSend<Bot>,
+    m: &Message,
+    e: ConvertError,
+) -> Result<(), RequestError> {
+    log::error!("{}", e);
+    if let ConvertError::Duration(_) = e {
+        use Callback::*;
+        let keyboard = [vec![SpeedUp], vec![TrimStart, TrimMiddle, TrimEnd]].map(|row| {
+            row.into_iter()
+                .map(|x| CallbackData::from(x).into())
+                .collect::<Vec<InlineKeyboardButton>>()
+        });
+        let text = format!(
+            "{}\nSpeed the clip up, keep a 3s window of it, or reply to the clip with the time to start from, e.g. 0:12.",
+            e
+        );
+        bot.send_message(m.chat_id(), text)
+            .reply_to_message_id(m.id)
+            .reply_markup(InlineKeyboardMarkup::new(keyboard))
+            .await?;
+    } else {
+        bot.send_message(m.chat_id(), e.to_string())
+            .reply_to_message_id(m.id)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn trim_reply_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    m_origin: Message,
+    trim: Trim,
+) -> Result<(), RequestError> {
+    let playback = Some(PlaybackProp::trim(trim));
+    let user_id = m.from().map(|u| u.id);
+    let effects = Effects::default();
+    let converted = convert_message(&m_origin, &bot, &store, &sources, None, playback, &effects);
+    match queued(&bot, &queue, user_id, &m, converted).await {
+        Ok(converted) => reply_converted(&bot, &store, &m_origin, converted).await,
+        Err(e) => reply_error(&bot, &m, e).await,
+    }
+}
+
+async fn sticker_handler(m: Message, bot: AutoSend<Bot>, queue: Queue) -> Result<(), RequestError> {
+    let r = async {
+        let (file_id, media_type) = get_sticker(&m).ok_or(ConvertError::MediaType)?;
+        let format = ExportFormat::choices(media_type)[0];
+        let user_id = m.from().map(|u| u.id);
+        let data = queued(
+            &bot,
+            &queue,
+            user_id,
+            &m,
+            export(&bot, file_id, media_type, format),
+        )
+        .await?;
+        let document = InputFile::memory(data).file_name(format.file_name());
+        anyhow::Ok((document, media_type, format))
+    }
+    .await;
+
+    match r {
+        Ok((document, media_type, format)) => {
             bot.send_document(m.chat_id(), document)
-                .caption(make_caption(layout, playback))
                 .reply_to_message_id(m.id)
-                .reply_markup(make_layout_keyboard(layout))
+                .reply_markup(make_export_keyboard(media_type, format))
                 .await?;
         }
         Err(e) => {
             log::error!("{}", e);
-            let mut res = bot
-                .send_message(m.chat_id(), e.to_string())
-                .reply_to_message_id(m.id);
-            if let ConvertError::Duration(_) = e {
-                let keyboard = vec![vec![Callback::SpeedUp.into()]];
-                res = res.reply_markup(InlineKeyboardMarkup::new(keyboard));
+            bot.send_message(m.chat_id(), e.to_string())
+                .reply_to_message_id(m.id)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Captions are limited to 1024 characters, so long reports are cut here.
+const MAX_REPORT_LENGTH: usize = 900;
+
+async fn archive_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    file_id: String,
+) -> Result<(), RequestError> {
+    let user_id = m.from().map(|u| u.id);
+    let settings = get_settings(&store, user_id).await;
+    // Entries take their turns in the queue one by one.
+    let r = convert_archive(&bot, &queue, user_id, &file_id, settings).await;
+    match r {
+        Ok((data, total, errors)) => {
+            let mut caption = format!("Converted {} of {} files.", total - errors.len(), total);
+            for (name, e) in &errors {
+                let line = format!("\n{}: {}", name, e);
+                if caption.len() + line.len() > MAX_REPORT_LENGTH {
+                    caption.push_str("\nSee report.txt for the rest.");
+                    break;
+                }
+                caption.push_str(&line);
             }
-            res.await?;
+            bot.send_document(
+                m.chat_id(),
+                InputFile::memory(data).file_name("stickers.zip"),
+            )
+            .caption(caption)
+            .reply_to_message_id(m.id)
+            .await?;
         }
-    };
+        Err(e) => {
+            log::error!("{}", e);
+            bot.send_message(m.chat_id(), e.to_string())
+                .reply_to_message_id(m.id)
+                .await?;
+        }
+    }
 
     Ok(())
 }
 
-pub async fn speed_up_handler(q: CallbackQuery, bot: AutoSend<Bot>) -> Result<True, RequestError> {
-    let r = (|| async {
+#[allow(clippy::too_many_arguments)]
+pub async fn message_handler(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    albums: Albums,
+    me: Me,
+) -> Result<(), RequestError> {
+    if let Some(group) = m.media_group_id().map(str::to_owned) {
+        if albums.push(&group, m) {
+            tokio::spawn(async move {
+                let messages = albums.collect(&group).await;
+                if let Err(e) = album_handler(bot, store, queue, sources, messages).await {
+                    log::error!("{}", e);
+                }
+            });
+        }
+        return Ok(());
+    }
+    // Updates are handled one at a time, so the conversion runs on its own
+    // and other users get their turn in the queue meanwhile.
+    tokio::spawn(async move {
+        if let Err(e) = handle_message(m, bot, store, queue, sources, latest, me).await {
+            log::error!("{}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn handle_message(
+    m: Message,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    me: Me,
+) -> Result<(), RequestError> {
+    if m.sticker().is_some() {
+        return sticker_handler(m, bot, queue).await;
+    }
+    if let Some(file_id) = get_archive(&m).map(str::to_owned) {
+        return archive_handler(m, bot, store, queue, file_id).await;
+    }
+    // Only replies to stickers converted by the bot change them, and only
+    // times trim the clips they reply to. Other replies are converted as
+    // any other message.
+    if let (Some(text), Some(m_reply)) = (m.text(), m.reply_to_message()) {
+        let m_reply = m_reply.to_owned();
+        let by_bot = m_reply.from().map(|u| u.id) == Some(me.user.id);
+        match get_state(&m_reply) {
+            Some(_) if by_bot => {
+                return if parse_color(text).is_ok() {
+                    color_reply_handler(m, bot, store, queue, sources, latest, m_reply).await
+                } else if text.parse::<EmojiProp>().is_ok() {
+                    emoji_reply_handler(m, bot, store, m_reply).await
+                } else {
+                    text_reply_handler(m, bot, store, queue, sources, latest, m_reply).await
+                };
+            }
+            None if get_media(&m_reply).is_some() => {
+                if let Ok(trim) = text.parse::<Trim>() {
+                    return trim_reply_handler(m, bot, store, queue, sources, m_reply, trim).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let user_id = m.from().map(|u| u.id);
+    let effects = Effects::default();
+    let converted = convert_message(&m, &bot, &store, &sources, None, None, &effects);
+    match queued(&bot, &queue, user_id, &m, converted).await {
+        Ok(converted) => reply_converted(&bot, &store, &m, converted).await,
+        Err(e) => reply_error(&bot, &m, e).await,
+    }
+}
+
+pub async fn playback_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
         let m = q.message.ok_or(PropsError::Message)?;
         let m_origin = m.reply_to_message().ok_or(PropsError::Origin)?.to_owned();
-        let (document, layout, playback) =
-            convert_message(&m_origin, &bot, None, Some(PlaybackProp { speed_up: true })).await?;
-        anyhow::Ok((m, m_origin, document, layout, playback))
-    })()
+        let playback = Some(PlaybackProp::try_from(data.callback)?);
+        let effects = Effects::default();
+        let converted =
+            convert_message(&m_origin, &bot, &store, &sources, None, playback, &effects);
+        let converted = queued(&bot, &queue, Some(q.from.id), &m, converted);
+        let converted = latest
+            .run((m.chat_id(), m.id), converted)
+            .await
+            .map_err(|_| ConvertError::Cancelled)??;
+        anyhow::Ok((m, m_origin, converted))
+    }
     .await;
 
     match r {
-        Ok((m, m_origin, document, layout, playback)) => {
+        Ok((m, m_origin, converted)) => {
             bot.delete_message(m.chat_id(), m.id).await?;
-            bot.send_document(m.chat_id(), document)
-                .caption(make_caption(layout, playback))
-                .reply_to_message_id(m_origin.id)
-                .reply_markup(make_layout_keyboard(layout))
+            reply_converted(&bot, &store, &m_origin, converted).await?;
+            bot.answer_callback_query(q.id).await
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            bot.answer_callback_query(q.id).text(e.to_string()).await
+        }
+    }
+}
+
+fn apply_layout_callback(state: State, callback: Callback) -> Result<State, CallbackError> {
+    match callback.kind() {
+        CallbackKind::Size => Ok(State {
+            layout: state.layout.reset_size(callback)?,
+            ..state
+        }),
+        CallbackKind::Position => Ok(State {
+            layout: state.layout.reset_alignment(callback)?,
+            ..state
+        }),
+        CallbackKind::Time => Ok(State {
+            playback: Some(callback.try_into()?),
+            ..state
+        }),
+        CallbackKind::Effect => Ok(State {
+            effects: state.effects.apply(callback)?,
+            ..state
+        }),
+        _ => Err(CallbackError::Incompatible),
+    }
+}
+
+fn make_album_summary(state: &State, total: usize, errors: &[(usize, ConvertError)]) -> String {
+    let mut lines = vec![format!(
+        "Converted {} of {} items.",
+        total - errors.len(),
+        total
+    )];
+    lines.push(make_caption(state, None, None));
+    lines.extend(errors.iter().map(|(i, e)| format!("#{}: {}", i + 1, e)));
+    lines.join("\n")
+}
+
+/// Converts every item of an album with the props picked for the first one,
+/// replies with the documents and a summary whose keyboard re-applies layout
+/// changes to all of them.
+async fn album_handler(
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    messages: Vec<Message>,
+) -> Result<(), RequestError> {
+    let first = match messages.first() {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    log::debug!("convert an album of {} items...", messages.len());
+
+    let (mut layout, mut playback) = (None, None);
+    let effects = Effects::default();
+    let mut converted = Vec::new();
+    let mut errors = Vec::new();
+    let user_id = first.from().map(|u| u.id);
+    let convert_all = async {
+        for (i, m) in messages.iter().enumerate() {
+            match convert_message(m, &bot, &store, &sources, layout, playback, &effects).await {
+                Ok((sticker, job)) => {
+                    layout = layout.or(Some(sticker.layout));
+                    playback = playback.or(sticker.playback);
+                    let media = get_media(m).expect("converted media");
+                    converted.push((media, sticker, job));
+                }
+                Err(e) => errors.push((i, e)),
+            }
+        }
+    };
+    queued(&bot, &queue, user_id, first, convert_all).await;
+    let state = State {
+        layout: layout.unwrap_or(LayoutProp::Large),
+        playback,
+        emoji: EmojiProp::default(),
+        effects: Effects::default(),
+    };
+    let text = make_album_summary(&state, messages.len(), &errors);
+    if converted.is_empty() {
+        bot.send_message(first.chat_id(), text)
+            .reply_to_message_id(first.id)
+            .await?;
+        return Ok(());
+    }
+
+    let (items, mut documents): (Vec<_>, Vec<_>) = converted
+        .into_iter()
+        .map(|((file_id, media_type), sticker, job)| {
+            ((file_id.to_owned(), media_type, job), sticker.file)
+        })
+        .unzip();
+    // Telegram only sends albums of two or more items.
+    let sent = match documents.pop() {
+        Some(document) if documents.is_empty() => vec![
+            bot.send_document(first.chat_id(), document)
+                .reply_to_message_id(first.id)
+                .await?,
+        ],
+        last => {
+            documents.extend(last);
+            let media = documents
+                .into_iter()
+                .map(|d| InputMedia::Document(InputMediaDocument::new(d)));
+            bot.send_media_group(first.chat_id(), media)
+                .reply_to_message_id(first.id)
+                .await?
+        }
+    };
+    let mut album_items = Vec::with_capacity(sent.len());
+    for ((source_file_id, media_type, job), m) in items.into_iter().zip(&sent) {
+        record_result(&store, job, m).await;
+        album_items.push(AlbumItem {
+            source_file_id,
+            media_type,
+            result_message_id: m.id,
+        });
+    }
+
+    let summary = bot
+        .send_message(first.chat_id(), text)
+        .reply_to_message_id(first.id)
+        .reply_markup(make_album_keyboard(&state))
+        .await?;
+    if let Err(e) = store
+        .add_album(first.chat_id(), summary.id, album_items)
+        .await
+    {
+        log::error!("Failed to record the album: {}", e);
+    }
+    Ok(())
+}
+
+pub async fn album_layout_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
+        let m = q.message.ok_or(PropsError::Message)?;
+        let state = data.state.ok_or(PropsError::Message)?;
+        let state = apply_layout_callback(state, data.callback)?;
+        let items = store.album(m.chat_id(), m.id).await?;
+        anyhow::ensure!(!items.is_empty(), PropsError::Origin);
+        anyhow::Ok((m, state, items))
+    }
+    .await;
+
+    let (m, state, items) = match r {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("{}", e);
+            return bot.answer_callback_query(q.id).text(e.to_string()).await;
+        }
+    };
+
+    let mut errors = Vec::new();
+    let convert_all = async {
+        for (i, item) in items.iter().enumerate() {
+            let r = convert_file(
+                &bot,
+                &store,
+                &sources,
+                Some(q.from.id),
+                m.chat_id(),
+                &item.source_file_id,
+                item.media_type,
+                Some(state.layout),
+                state.playback,
+                &state.effects,
+            )
+            .await;
+            match r {
+                Ok((sticker, job)) => {
+                    let sent = bot
+                        .edit_message_media(
+                            m.chat_id(),
+                            item.result_message_id,
+                            InputMedia::Document(InputMediaDocument::new(sticker.file)),
+                        )
+                        .await?;
+                    record_result(&store, job, &sent).await;
+                }
+                Err(e) => errors.push((i, e)),
+            }
+        }
+        Ok::<_, RequestError>(())
+    };
+    let convert_all = queued(&bot, &queue, Some(q.from.id), &m, convert_all);
+    match latest.run((m.chat_id(), m.id), convert_all).await {
+        Ok(r) => r?,
+        Err(_) => {
+            let text = ConvertError::Cancelled.to_string();
+            return bot.answer_callback_query(q.id).text(text).await;
+        }
+    }
+
+    bot.edit_message_text(
+        m.chat_id(),
+        m.id,
+        make_album_summary(&state, items.len(), &errors),
+    )
+    .reply_markup(make_album_keyboard(&state))
+    .await?;
+    bot.answer_callback_query(q.id).await
+}
+
+/// Converts the original of the sticker `m` again with `state` and puts the
+/// result in its place, unless a newer conversion of it comes along.
+#[allow(clippy::too_many_arguments)]
+async fn rerender(
+    bot: &AutoSend<Bot>,
+    store: &Store,
+    queue: &Queue,
+    sources: &Sources,
+    latest: &Latest,
+    user_id: Option<i64>,
+    m: &Message,
+    mut state: State,
+) -> anyhow::Result<()> {
+    let m_origin = m.reply_to_message().ok_or(PropsError::Origin)?;
+    if let Some(text) = state.effects.text.as_mut().filter(|t| t.text.is_empty()) {
+        text.text = store
+            .overlay_text(m.chat_id(), m.id)
+            .await?
+            .unwrap_or_default();
+    }
+    load_emoji(store, m, &mut state).await?;
+    let converted = convert_message(
+        m_origin,
+        bot,
+        store,
+        sources,
+        Some(state.layout),
+        state.playback,
+        &state.effects,
+    );
+    let converted = queued(bot, queue, user_id, m, converted);
+    let (sticker, job) = latest
+        .run((m.chat_id(), m.id), converted)
+        .await
+        .map_err(|_| ConvertError::Cancelled)??;
+    let state = State {
+        layout: sticker.layout,
+        playback: sticker.playback,
+        ..state
+    };
+    // Cached results do not know, but only results without effects are
+    // cached, and without a chroma key the caption before tells the same.
+    let translucent = sticker.translucent.or_else(|| {
+        get_state(m)
+            .filter(|s| s.effects.key.is_none())
+            .and_then(|_| get_translucent(m))
+    });
+
+    let sent = bot
+        .edit_message_media(
+            m.chat_id(),
+            m.id,
+            InputMedia::Document(InputMediaDocument::new(sticker.file)),
+        )
+        .await?;
+    record_result(store, job, &sent).await;
+    bot.edit_message_caption(m.chat_id(), m.id)
+        .caption(make_caption(&state, Some(sticker.size), translucent))
+        .await?;
+    bot.edit_message_reply_markup(m.chat_id(), m.id)
+        .reply_markup(make_keyboard(&state, media_type(m)))
+        .await?;
+    Ok(())
+}
+
+pub async fn layout_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
+        let m = q.message.ok_or(PropsError::Message)?;
+        let state = data
+            .state
+            .or_else(|| get_state(&m))
+            .ok_or(PropsError::Message)?;
+        let state = apply_layout_callback(state, data.callback)?;
+        let user_id = Some(q.from.id);
+        rerender(&bot, &store, &queue, &sources, &latest, user_id, &m, state).await
+    }
+    .await;
+
+    match r {
+        Ok(()) => bot.answer_callback_query(q.id).await,
+        Err(e) => {
+            log::error!("{}", e);
+            bot.answer_callback_query(q.id).text(e.to_string()).await
+        }
+    }
+}
+
+pub async fn emoji_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
+        let m = q.message.ok_or(PropsError::Message)?;
+        let mut state = data
+            .state
+            .or_else(|| get_state(&m))
+            .ok_or(PropsError::Message)?;
+        load_emoji(&store, &m, &mut state).await?;
+        let emoji = match data.callback {
+            Callback::Emoji(e) => Ok(state.emoji.toggle(e)?),
+            _ => Err(CallbackError::Incompatible),
+        }?;
+        store.set_emoji(m.chat_id(), m.id, &emoji).await?;
+        anyhow::Ok((m, State { emoji, ..state }))
+    }
+    .await;
+
+    match r {
+        Ok((m, state)) => {
+            bot.edit_message_caption(m.chat_id(), m.id)
+                .caption(make_caption(&state, file_size(&m), get_translucent(&m)))
+                .reply_markup(make_keyboard(&state, media_type(&m)))
                 .await?;
             bot.answer_callback_query(q.id).await
         }
@@ -186,38 +1547,69 @@ pub async fn speed_up_handler(q: CallbackQuery, bot: AutoSend<Bot>) -> Result<Tr
     }
 }
 
-pub async fn layout_handler(q: CallbackQuery, bot: AutoSend<Bot>) -> Result<True, RequestError> {
-    let r = (|| async {
+pub async fn setting_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
         let m = q.message.ok_or(PropsError::Message)?;
-        let m_origin = m.reply_to_message().ok_or(PropsError::Origin)?.to_owned();
-        let (layout, playback) = get_props(&m);
-        let layout = layout.ok_or(PropsError::Message)?;
-        let callback: Callback = q.data.unwrap_or_default().parse()?;
-        let layout = match callback.kind() {
-            CallbackKind::Size => layout.reset_size(callback),
-            CallbackKind::Position => layout.reset_alignment(callback),
+        let setting = match data.callback {
+            Callback::Setting(setting) => Ok(setting),
             _ => Err(CallbackError::Incompatible),
         }?;
+        let settings = store.settings(q.from.id).await?.apply(setting);
+        store.set_settings(q.from.id, &settings).await?;
+        anyhow::Ok((m, settings))
+    }
+    .await;
+
+    match r {
+        Ok((m, settings)) => {
+            bot.edit_message_reply_markup(m.chat_id(), m.id)
+                .reply_markup(make_settings_keyboard(&settings))
+                .await?;
+            bot.answer_callback_query(q.id).await
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            bot.answer_callback_query(q.id).text(e.to_string()).await
+        }
+    }
+}
 
-        let (document, layout, playback) =
-            convert_message(&m_origin, &bot, Some(layout), playback).await?;
-        anyhow::Result::<_>::Ok((m, document, layout, playback))
-    })()
+pub async fn export_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    queue: Queue,
+    data: CallbackData,
+) -> Result<True, RequestError> {
+    let r = async {
+        let m = q.message.ok_or(PropsError::Message)?;
+        let m_origin = m.reply_to_message().ok_or(PropsError::Origin)?;
+        let (file_id, media_type) = get_sticker(m_origin).ok_or(PropsError::Origin)?;
+        let format = match data.callback {
+            Callback::Export(format) => Ok(format),
+            _ => Err(CallbackError::Incompatible),
+        }?;
+        let data = export(&bot, file_id, media_type, format);
+        let data = queued(&bot, &queue, Some(q.from.id), &m, data).await?;
+        let document = InputFile::memory(data).file_name(format.file_name());
+        anyhow::Ok((m, document, media_type, format))
+    }
     .await;
 
     match r {
-        Ok((m, document, layout, playback)) => {
+        Ok((m, document, media_type, format)) => {
             bot.edit_message_media(
                 m.chat_id(),
                 m.id,
                 InputMedia::Document(InputMediaDocument::new(document)),
             )
             .await?;
-            bot.edit_message_caption(m.chat_id(), m.id)
-                .caption(make_caption(layout, playback))
-                .await?;
             bot.edit_message_reply_markup(m.chat_id(), m.id)
-                .reply_markup(make_layout_keyboard(layout))
+                .reply_markup(make_export_keyboard(media_type, format))
                 .await?;
             bot.answer_callback_query(q.id).await
         }
@@ -228,11 +1620,58 @@ pub async fn layout_handler(q: CallbackQuery, bot: AutoSend<Bot>) -> Result<True
     }
 }
 
-pub async fn callback_handler(q: CallbackQuery, bot: AutoSend<Bot>) -> Result<(), RequestError> {
-    match q.data.to_owned().unwrap_or_default().parse::<Callback>() {
-        Ok(callback) => match callback.kind() {
-            CallbackKind::Size | CallbackKind::Position => layout_handler(q, bot).await,
-            CallbackKind::Time => speed_up_handler(q, bot).await,
+pub async fn callback_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+) -> Result<(), RequestError> {
+    // Like messages, buttons that convert must not hold up other updates.
+    tokio::spawn(async move {
+        if let Err(e) = handle_callback(q, bot, store, queue, sources, latest).await {
+            log::error!("{}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn handle_callback(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    store: Store,
+    queue: Queue,
+    sources: Sources,
+    latest: Latest,
+) -> Result<(), RequestError> {
+    match q
+        .data
+        .to_owned()
+        .unwrap_or_default()
+        .parse::<CallbackData>()
+    {
+        Ok(data) => match data.callback.kind() {
+            CallbackKind::Size | CallbackKind::Position | CallbackKind::Time
+                if data.state.is_some() && q.message.as_ref().and_then(|m| m.text()).is_some() =>
+            {
+                album_layout_handler(q, bot, store, queue, sources, latest, data).await
+            }
+            CallbackKind::Size | CallbackKind::Position => {
+                layout_handler(q, bot, store, queue, sources, latest, data).await
+            }
+            CallbackKind::Time if data.state.is_some() => {
+                layout_handler(q, bot, store, queue, sources, latest, data).await
+            }
+            CallbackKind::Time => {
+                playback_handler(q, bot, store, queue, sources, latest, data).await
+            }
+            CallbackKind::Effect => {
+                layout_handler(q, bot, store, queue, sources, latest, data).await
+            }
+            CallbackKind::Emoji => emoji_handler(q, bot, store, data).await,
+            CallbackKind::Setting => setting_handler(q, bot, store, data).await,
+            CallbackKind::Export => export_handler(q, bot, queue, data).await,
         },
         Err(e) => bot.answer_callback_query(q.id).text(e.to_string()).await,
     }
@@ -0,0 +1,181 @@
+use futures::future::{AbortHandle, Abortable, Aborted};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::watch;
+
+/// Conversions run at once unless `CONVERT_WORKERS` says otherwise.
+pub const DEFAULT_WORKERS: usize = 2;
+
+struct Entry {
+    id: u64,
+    round: u64,
+    position: watch::Sender<usize>,
+}
+
+struct Inner {
+    workers: usize,
+    running: usize,
+    /// Waiting jobs, ordered by round and then by arrival.
+    waiting: Vec<Entry>,
+    /// Round of the job that started last.
+    round: u64,
+    /// Round of the latest job of each user, kept while it is not behind.
+    last_round: HashMap<Option<i64>, u64>,
+    next_id: u64,
+}
+
+/// Conversions waiting for one of a fixed number of workers. Users take
+/// turns, so one user sending many files does not hold up everybody else.
+#[derive(Clone)]
+pub struct Queue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// A place in the [`Queue`]. It holds a worker once it reaches the front,
+/// until it is dropped.
+pub struct Ticket {
+    queue: Queue,
+    id: u64,
+    position: watch::Receiver<usize>,
+}
+
+/// The conversion running for each `(chat_id, message_id)`, by its id.
+type Jobs = HashMap<(i64, i32), (u64, AbortHandle)>;
+
+/// Conversions for a message, of which only the latest one is wanted. A
+/// newer conversion for the same message aborts the one before it.
+#[derive(Clone, Default)]
+pub struct Latest {
+    jobs: Arc<Mutex<Jobs>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Inner {
+    /// Starts waiting jobs while there are idle workers and tells the rest
+    /// where they are.
+    fn promote(&mut self) {
+        while self.running < self.workers && !self.waiting.is_empty() {
+            let entry = self.waiting.remove(0);
+            self.round = entry.round;
+            self.running += 1;
+            let _ = entry.position.send(0);
+        }
+        let round = self.round;
+        self.last_round.retain(|_, r| *r >= round);
+        for (i, entry) in self.waiting.iter().enumerate() {
+            if *entry.position.borrow() != i + 1 {
+                let _ = entry.position.send(i + 1);
+            }
+        }
+    }
+}
+
+impl Queue {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                workers: workers.max(1),
+                running: 0,
+                waiting: Vec::new(),
+                round: 0,
+                last_round: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("queue is not poisoned")
+    }
+
+    /// Queues a job of the user. Each further job of a user who already has
+    /// one queued or running goes a round later.
+    pub fn join(&self, user_id: Option<i64>) -> Ticket {
+        let mut inner = self.inner();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let round = match inner.last_round.get(&user_id) {
+            Some(&r) => (r + 1).max(inner.round),
+            None => inner.round,
+        };
+        inner.last_round.insert(user_id, round);
+
+        let (tx, rx) = watch::channel(usize::MAX);
+        let at = inner.waiting.partition_point(|e| e.round <= round);
+        inner.waiting.insert(
+            at,
+            Entry {
+                id,
+                round,
+                position: tx,
+            },
+        );
+        inner.promote();
+        Ticket {
+            queue: self.clone(),
+            id,
+            position: rx,
+        }
+    }
+}
+
+impl Ticket {
+    /// Place in the queue counting from 1, or 0 once the job holds a worker.
+    pub fn position(&self) -> usize {
+        *self.position.borrow()
+    }
+
+    /// Waits for the position to change and returns whether it may change
+    /// again.
+    pub async fn changed(&mut self) -> bool {
+        self.position.changed().await.is_ok()
+    }
+
+    /// Waits until the job holds a worker.
+    pub async fn ready(&mut self) {
+        while self.position() > 0 && self.changed().await {}
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut inner = self.queue.inner();
+        match inner.waiting.iter().position(|e| e.id == self.id) {
+            Some(i) => {
+                inner.waiting.remove(i);
+            }
+            None => inner.running -= 1,
+        }
+        inner.promote();
+    }
+}
+
+impl Latest {
+    fn jobs(&self) -> std::sync::MutexGuard<'_, Jobs> {
+        self.jobs.lock().expect("jobs are not poisoned")
+    }
+
+    /// Runs the conversion for the message `(chat_id, message_id)` unless a
+    /// newer one for the same message comes along first.
+    pub async fn run<T>(&self, key: (i64, i32), f: impl Future<Output = T>) -> Result<T, Aborted> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (handle, registration) = AbortHandle::new_pair();
+        if let Some((_, previous)) = self.jobs().insert(key, (id, handle)) {
+            log::debug!("abort the previous conversion for {:?}", key);
+            previous.abort();
+        }
+
+        let r = Abortable::new(f, registration).await;
+        let mut jobs = self.jobs();
+        if jobs.get(&key).map(|(i, _)| *i) == Some(id) {
+            jobs.remove(&key);
+        }
+        r
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{
+    convert::{download_file, get_file, probe},
+    errors::*,
+    media_info::MediaInfo,
+};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use teloxide::{adaptors::AutoSend, types::File as TgFile, Bot};
+use tempfile::TempPath;
+use tokio::sync::OnceCell;
+
+/// How long a source is kept after it was last converted. Users changing
+/// the layout of a sticker usually do so within this time.
+const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+/// Most sources kept at once. The least recently used ones go first.
+const MAX_SOURCES: usize = 64;
+
+/// An original file sent for conversion. It is downloaded, and videos are
+/// probed, at most once however often it is converted.
+pub struct Source {
+    file: TgFile,
+    path: OnceCell<TempPath>,
+    info: OnceCell<MediaInfo>,
+}
+
+/// Each source by its `file_id`, with when it was last used.
+type Cache = HashMap<String, (Arc<Source>, Instant)>;
+
+/// Originals converted recently, keyed by `file_id`.
+#[derive(Clone, Default)]
+pub struct Sources {
+    sources: Arc<Mutex<Cache>>,
+}
+
+impl Source {
+    /// The id that stays the same for the file over time and across bots.
+    pub fn unique_id(&self) -> &str {
+        &self.file.file_unique_id
+    }
+
+    pub async fn path(&self, bot: &AutoSend<Bot>) -> Result<&Path, ConvertError> {
+        let path = self
+            .path
+            .get_or_try_init(|| download_file(bot, &self.file))
+            .await?;
+        Ok(path.as_ref())
+    }
+
+    pub async fn info(&self, bot: &AutoSend<Bot>) -> Result<&MediaInfo, ConvertError> {
+        let path = self.path(bot).await?;
+        self.info.get_or_try_init(|| probe(path)).await
+    }
+}
+
+impl Sources {
+    fn sources(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.sources.lock().expect("sources are not poisoned")
+    }
+
+    /// Drops the sources not used within [`SESSION_TTL`], along with their
+    /// downloaded files once no conversion uses them.
+    fn evict_expired(&self) {
+        let now = Instant::now();
+        self.sources()
+            .retain(|_, (_, used)| now.duration_since(*used) < SESSION_TTL);
+    }
+
+    /// Evicts expired sources every minute, so that an idle bot does not keep
+    /// them on disk.
+    pub fn spawn_eviction(&self) {
+        let sources = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                sources.evict_expired();
+            }
+        });
+    }
+
+    /// Returns the source of the file, looking it up if it is not known yet.
+    pub async fn get(
+        &self,
+        bot: &AutoSend<Bot>,
+        file_id: &str,
+    ) -> Result<Arc<Source>, ConvertError> {
+        self.evict_expired();
+        if let Some((source, used)) = self.sources().get_mut(file_id) {
+            *used = Instant::now();
+            return Ok(source.clone());
+        }
+
+        let source = Arc::new(Source {
+            file: get_file(bot, file_id).await?,
+            path: OnceCell::new(),
+            info: OnceCell::new(),
+        });
+        let mut sources = self.sources();
+        while sources.len() >= MAX_SOURCES {
+            let oldest = sources
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(file_id, _)| file_id.clone())
+                .expect("sources are not empty");
+            sources.remove(&oldest);
+        }
+        sources.insert(file_id.to_owned(), (source.clone(), Instant::now()));
+        Ok(source)
+    }
+}
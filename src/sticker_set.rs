@@ -0,0 +1,167 @@
+use crate::{
+    convert::{download, export, sticker_png},
+    errors::*,
+    types::*,
+};
+use serde_json::json;
+use std::io::{Cursor, Write};
+use teloxide::{
+    adaptors::AutoSend,
+    prelude::Requester,
+    types::{InputFile, InputSticker, Me, Sticker},
+    Bot,
+};
+use zip::{write::FileOptions, ZipWriter};
+
+const DEFAULT_EMOJI: &str = "🙂";
+const LINK_PREFIX: &str = "t.me/addstickers/";
+
+/// The name of a set made by the bot, which must end in `_by_<bot username>`.
+pub fn full_name(me: &Me, name: &str) -> String {
+    let suffix = format!("_by_{}", me.user.username.as_deref().unwrap_or_default());
+    if name.to_lowercase().ends_with(&suffix.to_lowercase()) {
+        name.to_owned()
+    } else {
+        format!("{}{}", name, suffix)
+    }
+}
+
+fn emojis(emoji: &EmojiProp) -> String {
+    if emoji.is_empty() {
+        DEFAULT_EMOJI.to_owned()
+    } else {
+        emoji.emojis.concat()
+    }
+}
+
+async fn input_sticker(
+    bot: &AutoSend<Bot>,
+    file_id: &str,
+    media_type: MediaType,
+) -> anyhow::Result<InputSticker> {
+    let tmp_path = download(bot, file_id).await?;
+    Ok(match media_type {
+        // Image stickers may be WebP, but sets only take PNG.
+        MediaType::Image => {
+            InputSticker::Png(InputFile::memory(sticker_png(&tmp_path)?).file_name("sticker.png"))
+        }
+        MediaType::Video => InputSticker::Webm(
+            InputFile::memory(tokio::fs::read(&tmp_path).await?).file_name("sticker.webm"),
+        ),
+        MediaType::Animated => InputSticker::Tgs(
+            InputFile::memory(tokio::fs::read(&tmp_path).await?).file_name("sticker.tgs"),
+        ),
+    })
+}
+
+pub async fn create_sticker_set(
+    bot: &AutoSend<Bot>,
+    user_id: i64,
+    name: String,
+    title: &str,
+    file_id: &str,
+    media_type: MediaType,
+    emoji: &EmojiProp,
+) -> anyhow::Result<String> {
+    let sticker = input_sticker(bot, file_id, media_type).await?;
+    log::debug!("create {} sticker set {}...", media_type, name);
+    bot.create_new_sticker_set(user_id, &name, title, sticker, emojis(emoji))
+        .await?;
+    Ok(name)
+}
+
+pub async fn add_to_sticker_set(
+    bot: &AutoSend<Bot>,
+    user_id: i64,
+    name: String,
+    file_id: &str,
+    media_type: MediaType,
+    emoji: &EmojiProp,
+) -> anyhow::Result<String> {
+    let set = bot.get_sticker_set(&name).await?;
+    let set_type = match (set.is_animated, set.is_video) {
+        (false, false) => Some(MediaType::Image),
+        (false, true) => Some(MediaType::Video),
+        (true, false) => Some(MediaType::Animated),
+        _ => None,
+    };
+    anyhow::ensure!(
+        set_type == Some(media_type),
+        StickerSetError::Incompatible(media_type, name)
+    );
+    let sticker = input_sticker(bot, file_id, media_type).await?;
+    log::debug!("add a {} sticker to {}...", media_type, name);
+    bot.add_sticker_to_set(user_id, &name, sticker, emojis(emoji))
+        .await?;
+    Ok(name)
+}
+
+fn media_type(sticker: &Sticker) -> MediaType {
+    match (sticker.is_animated, sticker.is_video) {
+        (true, _) => MediaType::Animated,
+        (_, true) => MediaType::Video,
+        _ => MediaType::Image,
+    }
+}
+
+fn extension(media_type: MediaType) -> &'static str {
+    match media_type {
+        MediaType::Image => "webp",
+        MediaType::Video => "webm",
+        MediaType::Animated => "tgs",
+    }
+}
+
+/// Downloads every sticker of a set, converted to `format` if given, and
+/// packs them into a ZIP archive along with a `manifest.json`.
+pub async fn export_sticker_set(
+    bot: &AutoSend<Bot>,
+    name: &str,
+    format: Option<ExportFormat>,
+) -> anyhow::Result<InputFile> {
+    let name = name
+        .split_once(LINK_PREFIX)
+        .map_or(name, |(_, name)| name)
+        .trim_end_matches('/');
+    let set = bot.get_sticker_set(name).await?;
+    log::debug!("export {} stickers of {}...", set.stickers.len(), set.name);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut manifest = Vec::new();
+    for (i, sticker) in set.stickers.iter().enumerate() {
+        let media_type = media_type(sticker);
+        let (file_name, data) = match format {
+            Some(format) => {
+                anyhow::ensure!(
+                    ExportFormat::choices(media_type).contains(&format),
+                    StickerSetError::Export(format, media_type)
+                );
+                let data = export(bot, &sticker.file_id, media_type, format).await?;
+                (format!("{:03}.{}", i + 1, format.extension()), data)
+            }
+            None => {
+                let tmp_path = download(bot, &sticker.file_id).await?;
+                let data = tokio::fs::read(&tmp_path).await?;
+                (format!("{:03}.{}", i + 1, extension(media_type)), data)
+            }
+        };
+        zip.start_file(&file_name, FileOptions::default())?;
+        zip.write_all(&data)?;
+        manifest.push(json!({
+            "index": i + 1,
+            "file": file_name,
+            "emoji": sticker.emoji,
+            "type": media_type.to_string(),
+        }));
+    }
+    let manifest = json!({
+        "name": set.name,
+        "title": set.title,
+        "stickers": manifest,
+    });
+    zip.start_file("manifest.json", FileOptions::default())?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+
+    let data = zip.finish()?.into_inner();
+    Ok(InputFile::memory(data).file_name(format!("{}.zip", set.name)))
+}
@@ -0,0 +1,403 @@
+use crate::{errors::*, types::*};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER,
+    chat_id INTEGER NOT NULL,
+    source_file_id TEXT NOT NULL,
+    media_type TEXT NOT NULL,
+    layout TEXT,
+    playback TEXT,
+    result_file_id TEXT,
+    error TEXT,
+    started_at INTEGER NOT NULL,
+    converted_at INTEGER,
+    sent_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS jobs_user ON jobs (user_id, started_at);
+CREATE TABLE IF NOT EXISTS settings (
+    user_id INTEGER PRIMARY KEY,
+    size TEXT NOT NULL,
+    alignment TEXT NOT NULL,
+    playback TEXT NOT NULL,
+    format TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS album_items (
+    chat_id INTEGER NOT NULL,
+    summary_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    source_file_id TEXT NOT NULL,
+    media_type TEXT NOT NULL,
+    result_message_id INTEGER NOT NULL,
+    PRIMARY KEY (chat_id, summary_id, position)
+);
+CREATE TABLE IF NOT EXISTS results (
+    source_unique_id TEXT NOT NULL,
+    layout TEXT NOT NULL,
+    playback TEXT NOT NULL,
+    encoder TEXT NOT NULL,
+    result_file_id TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    PRIMARY KEY (source_unique_id, layout, playback, encoder)
+);
+CREATE TABLE IF NOT EXISTS overlay_texts (
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    PRIMARY KEY (chat_id, message_id)
+);
+CREATE TABLE IF NOT EXISTS sticker_emoji (
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    emoji TEXT NOT NULL,
+    PRIMARY KEY (chat_id, message_id)
+);
+"#;
+
+/// Persistent record of conversion jobs, backed by a single SQLite file.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// A converted item of an album, found through the album's summary message.
+pub struct AlbumItem {
+    pub source_file_id: String,
+    pub media_type: MediaType,
+    pub result_message_id: i32,
+}
+
+/// Identifies a conversion whose result can be sent again as is.
+#[derive(Debug, Clone)]
+pub struct ResultKey {
+    pub source_unique_id: String,
+    pub layout: LayoutProp,
+    pub playback: Option<PlaybackProp>,
+    pub encoder: String,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+impl Store {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` with the connection on a blocking thread, so that waiting for
+    /// SQLite or for the lock holds up no task but the caller.
+    async fn with_conn<T, F>(&self, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().expect("store connection is not poisoned");
+            f(&mut conn)
+        })
+        .await
+        .expect("store task does not panic")
+    }
+
+    pub async fn start_job(
+        &self,
+        user_id: Option<i64>,
+        chat_id: i64,
+        source_file_id: &str,
+        media_type: MediaType,
+    ) -> rusqlite::Result<i64> {
+        let source_file_id = source_file_id.to_owned();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO jobs (user_id, chat_id, source_file_id, media_type, started_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    user_id,
+                    chat_id,
+                    source_file_id,
+                    media_type.to_string(),
+                    now()
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    pub async fn finish_job(
+        &self,
+        id: i64,
+        result: Result<(LayoutProp, Option<PlaybackProp>), &ConvertError>,
+    ) -> rusqlite::Result<()> {
+        let (layout, playback, error) = match result {
+            Ok((layout, playback)) => (
+                Some(layout.to_string()),
+                playback.map(|p| p.to_string()),
+                None,
+            ),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE jobs SET layout = ?2, playback = ?3, error = ?4, converted_at = ?5
+                 WHERE id = ?1",
+                params![id, layout, playback, error, now()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_result(&self, id: i64, result_file_id: &str) -> rusqlite::Result<()> {
+        let result_file_id = result_file_id.to_owned();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE jobs SET result_file_id = ?2, sent_at = ?3 WHERE id = ?1",
+                params![id, result_file_id, now()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the `file_id` and size of the result sent for the key before.
+    pub async fn cached_result(&self, key: &ResultKey) -> rusqlite::Result<Option<(String, u64)>> {
+        let key = key.clone();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT result_file_id, size FROM results
+                 WHERE source_unique_id = ?1 AND layout = ?2 AND playback = ?3 AND encoder = ?4",
+                params![
+                    key.source_unique_id,
+                    key.layout.to_string(),
+                    key.playback.map(|p| p.to_string()).unwrap_or_default(),
+                    key.encoder
+                ],
+                |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    pub async fn cache_result(
+        &self,
+        key: &ResultKey,
+        result_file_id: &str,
+        size: u64,
+    ) -> rusqlite::Result<()> {
+        let (key, result_file_id) = (key.clone(), result_file_id.to_owned());
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO results
+                 (source_unique_id, layout, playback, encoder, result_file_id, size)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key.source_unique_id,
+                    key.layout.to_string(),
+                    key.playback.map(|p| p.to_string()).unwrap_or_default(),
+                    key.encoder,
+                    result_file_id,
+                    size as i64
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Remembers the text drawn over the sticker in the message, which is too
+    /// long to keep in its buttons.
+    pub async fn set_overlay_text(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        text: &str,
+    ) -> rusqlite::Result<()> {
+        let text = text.to_owned();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO overlay_texts (chat_id, message_id, text)
+                 VALUES (?1, ?2, ?3)",
+                params![chat_id, message_id, text],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn overlay_text(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+    ) -> rusqlite::Result<Option<String>> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT text FROM overlay_texts WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Remembers the emoji of the sticker in the message, which are too many
+    /// to keep in its buttons.
+    pub async fn set_emoji(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        emoji: &EmojiProp,
+    ) -> rusqlite::Result<()> {
+        let emoji = emoji.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sticker_emoji (chat_id, message_id, emoji)
+                 VALUES (?1, ?2, ?3)",
+                params![chat_id, message_id, emoji],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn emoji(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+    ) -> rusqlite::Result<Option<EmojiProp>> {
+        let emoji = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT emoji FROM sticker_emoji WHERE chat_id = ?1 AND message_id = ?2",
+                    params![chat_id, message_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+        Ok(emoji.and_then(|emoji| emoji.parse().ok()))
+    }
+
+    pub async fn add_album(
+        &self,
+        chat_id: i64,
+        summary_id: i32,
+        items: Vec<AlbumItem>,
+    ) -> rusqlite::Result<()> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for (position, item) in items.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO album_items
+                     (chat_id, summary_id, position, source_file_id, media_type, result_message_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        chat_id,
+                        summary_id,
+                        position,
+                        item.source_file_id,
+                        item.media_type.to_string(),
+                        item.result_message_id
+                    ],
+                )?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    pub async fn album(&self, chat_id: i64, summary_id: i32) -> rusqlite::Result<Vec<AlbumItem>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT source_file_id, media_type, result_message_id FROM album_items
+                 WHERE chat_id = ?1 AND summary_id = ?2 ORDER BY position",
+            )?;
+            let rows = stmt.query_map(params![chat_id, summary_id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            })?;
+            rows.filter_map(|row| match row {
+                Ok((source_file_id, media_type, result_message_id)) => {
+                    let media_type = media_type.parse().ok()?;
+                    Some(Ok(AlbumItem {
+                        source_file_id,
+                        media_type,
+                        result_message_id,
+                    }))
+                }
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+        })
+        .await
+    }
+
+    pub async fn settings(&self, user_id: i64) -> rusqlite::Result<Settings> {
+        let row = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT size, alignment, playback, format FROM settings WHERE user_id = ?1",
+                    params![user_id],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                        ))
+                    },
+                )
+                .optional()
+            })
+            .await?;
+        let default = Settings::default();
+        Ok(match row {
+            Some((size, alignment, playback, format)) => Settings {
+                size: size.parse().unwrap_or(default.size),
+                alignment: alignment.parse().unwrap_or(default.alignment),
+                playback: playback.parse().unwrap_or(default.playback),
+                format: format.parse().unwrap_or(default.format),
+            },
+            None => default,
+        })
+    }
+
+    pub async fn set_settings(&self, user_id: i64, settings: &Settings) -> rusqlite::Result<()> {
+        let settings = *settings;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (user_id, size, alignment, playback, format)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    user_id,
+                    settings.size.to_string(),
+                    settings.alignment.to_string(),
+                    settings.playback.to_string(),
+                    settings.format.to_string()
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
@@ -1,12 +1,57 @@
-use crate::errors::*;
-use std::{cmp::Ordering, fmt::Display, str::FromStr};
-use teloxide::{types::InlineKeyboardButton, utils::command::BotCommand};
+use crate::{
+    errors::*,
+    geometry::{self, Anchor, Frame, Placement, STICKER_SIDE},
+};
+use std::{fmt::Display, str::FromStr};
+use teloxide::{
+    types::InlineKeyboardButton,
+    utils::command::{BotCommand, ParseError},
+};
 
 #[derive(Debug, BotCommand, Clone)]
 #[command(rename = "lowercase")]
 pub enum Command {
     Start,
     Help,
+    Settings,
+    #[command(parse_with = "parse_new_pack")]
+    NewPack(String, String),
+    AddTo(String),
+    #[command(parse_with = "parse_export")]
+    Export(String, Option<ExportFormat>),
+}
+
+fn parse_new_pack(s: String) -> Result<(String, String), ParseError> {
+    let mut args = s.trim().splitn(2, ' ');
+    let name = args.next().filter(|x| !x.is_empty());
+    let title = args.next().map(str::trim).filter(|x| !x.is_empty());
+    match (name, title) {
+        (Some(name), Some(title)) => Ok((name.to_owned(), title.to_owned())),
+        (name, _) => Err(ParseError::TooFewArguments {
+            expected: 2,
+            found: name.map_or(0, |_| 1),
+            message: "Usage: /newpack <name> <title>".to_owned(),
+        }),
+    }
+}
+
+fn parse_export(s: String) -> Result<(String, Option<ExportFormat>), ParseError> {
+    let mut args = s.split_whitespace();
+    let name = args.next().ok_or_else(|| ParseError::TooFewArguments {
+        expected: 1,
+        found: 0,
+        message: "Usage: /export <name or link> [png|gif|mp4|json]".to_owned(),
+    })?;
+    let format = args
+        .next()
+        .map(|f| {
+            ExportFormat::ALL
+                .into_iter()
+                .find(|x| x.text().eq_ignore_ascii_case(f))
+                .ok_or_else(|| ParseError::IncorrectFormat(PropsError::Parse(f.to_owned()).into()))
+        })
+        .transpose()?;
+    Ok((name.to_owned(), format))
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -14,48 +59,316 @@ pub enum Callback {
     Small,
     Medium,
     Large,
+    CustomEmoji,
     Left,
     Center,
     Right,
+    Top,
+    Middle,
+    Bottom,
+    Shorter,
+    Taller,
     SpeedUp,
+    TrimStart,
+    TrimMiddle,
+    TrimEnd,
+    Emoji(&'static str),
+    Setting(Setting),
+    Export(ExportFormat),
+    RemoveBackground,
+    ToleranceDown,
+    ToleranceUp,
+    ToggleOutline,
+    NextOutlineColor,
+    Thinner,
+    Thicker,
+    RemoveText,
+    MoveText,
+    NextTextStyle,
+    NextCrop,
+    NudgeLeft,
+    NudgeRight,
+    NudgeUp,
+    NudgeDown,
+    ZoomIn,
+    ZoomOut,
 }
 #[derive(Debug)]
 pub enum CallbackKind {
     Size,
     Position,
     Time,
+    Emoji,
+    Setting,
+    Export,
+    Effect,
+}
+
+pub const SUGGESTED_EMOJI: [&str; 8] = ["😀", "😂", "😍", "😢", "😡", "👍", "❤️", "🎉"];
+/// Telegram takes at most this many emoji for one sticker.
+const MAX_EMOJI: usize = 20;
+/// Free heights of the box are multiples of this, in pixels.
+const HEIGHT_STEP: u32 = 32;
+/// Tolerance of a chroma key until the user changes it, in percent.
+pub const DEFAULT_TOLERANCE: u8 = 20;
+/// Change of the tolerance per button press, in percent.
+const TOLERANCE_STEP: u8 = 10;
+/// Widths of outlines, in pixels of a 512px sticker.
+const MIN_OUTLINE_WIDTH: u8 = 4;
+const MAX_OUTLINE_WIDTH: u8 = 24;
+const OUTLINE_WIDTH_STEP: u8 = 4;
+/// Zoom levels of a manual crop, each showing half the media again less.
+pub const MAX_ZOOM: u8 = 4;
+/// Steps a manual crop can be nudged from the centre to either side.
+pub const MAX_NUDGE: i8 = 5;
+/// Longest text drawn over a sticker, in characters.
+pub const MAX_TEXT_LENGTH: usize = 100;
+/// Length of the window cut out of long clips, and the longest clip accepted
+/// as is.
+pub const MAX_DURATION: f32 = 3.0;
+/// Bump whenever the meaning of an existing field in [`State`] changes.
+/// Appending a field does not need a bump, as missing fields take defaults.
+const STATE_VERSION: u8 = 1;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct CallbackData {
+    pub callback: Callback,
+    pub state: Option<State>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MediaType {
     Image,
     Video,
+    /// A Lottie animation, sent to Telegram as a gzipped TGS file.
+    Animated,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum Alignment {
     Left,
+    #[default]
     Center,
     Right,
 }
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum VerticalAlignment {
+    Top,
+    #[default]
+    Middle,
+    Bottom,
+}
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LayoutProp {
-    Small(Alignment),
-    Medium(Alignment),
+    Small(Alignment, VerticalAlignment),
+    Medium(Alignment, VerticalAlignment),
+    /// A box of any multiple of [`HEIGHT_STEP`] high, up to 512px.
+    Height(u32, Alignment, VerticalAlignment),
     Large,
+    /// A Telegram custom emoji, which must be exactly 100px\*100px.
+    CustomEmoji,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct PlaybackProp {
     pub speed_up: bool,
+    pub trim: Option<Trim>,
+}
+
+/// Where to cut a window of [`MAX_DURATION`] out of a long clip.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Trim {
+    Start,
+    Middle,
+    End,
+    /// Offset in seconds, kept to a tenth of a second.
+    At(f32),
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct EmojiProp {
+    pub emojis: Vec<String>,
+}
+
+/// Makes a colour of the media transparent.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ChromaKey {
+    /// The colour to remove, or `None` for the one in the corners.
+    pub color: Option<[u8; 3]>,
+    /// How far a colour may be from it and still be removed, in percent.
+    pub tolerance: u8,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OutlineColor {
+    #[default]
+    White,
+    Black,
+    Yellow,
+    Blue,
+}
+
+/// A border drawn around the opaque parts of the media, like on a die-cut
+/// sticker.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Outline {
+    pub color: OutlineColor,
+    /// Width in pixels of a 512px sticker, scaled for smaller ones.
+    pub width: u8,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TextPosition {
+    Top,
+    #[default]
+    Bottom,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TextStyle {
+    /// White capitals with a black border.
+    #[default]
+    Meme,
+    /// Yellow with a black border.
+    Subtitle,
+    /// Black with a white border.
+    Dark,
+}
+
+/// Text drawn over the media, like on a meme.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TextOverlay {
+    /// The text itself, which does not fit in callback data. It is kept in
+    /// the store instead, and left empty in states read from buttons.
+    pub text: String,
+    pub position: TextPosition,
+    pub style: TextStyle,
+}
+
+/// Which part of the media ends up in the sticker.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Crop {
+    /// All of it, padded to fill the box if needed.
+    #[default]
+    Fit,
+    /// As much as fills the box, cut evenly off both sides.
+    Fill,
+    /// A square out of the middle.
+    Square,
+    /// A window picked with the nudge buttons.
+    Manual {
+        /// Each level shows half the media again less, from 0 for all of it.
+        zoom: u8,
+        /// Steps from the centre towards the right and the bottom, from
+        /// `-MAX_NUDGE` at the left or top edge to `MAX_NUDGE` at the other.
+        x: i8,
+        y: i8,
+    },
+}
+
+/// Changes to the media itself, made before it is laid out.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Effects {
+    pub key: Option<ChromaKey>,
+    pub outline: Option<Outline>,
+    pub text: Option<TextOverlay>,
+    pub crop: Crop,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SizePref {
+    #[default]
+    Auto,
+    Small,
+    Medium,
+    Large,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum PlaybackPolicy {
+    /// Reject clips longer than 3s and offer to speed them up.
+    #[default]
+    Ask,
+    /// Speed up clips longer than 3s without asking.
+    SpeedUp,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    WebP,
+}
+
+/// Formats a sticker sent to the bot can be exported back to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExportFormat {
+    Png,
+    Gif,
+    Mp4,
+    /// The Lottie animation inside a TGS file, as it is.
+    Json,
+}
+
+/// Per-user defaults applied when a conversion has no explicit props.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Settings {
+    pub size: SizePref,
+    pub alignment: Alignment,
+    pub playback: PlaybackPolicy,
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Setting {
+    Size(SizePref),
+    Alignment(Alignment),
+    Playback(PlaybackPolicy),
+    Format(OutputFormat),
+}
+
+/// Everything needed to re-render a converted sticker, carried by its buttons.
+#[derive(Debug, PartialEq, Clone)]
+pub struct State {
+    pub layout: LayoutProp,
+    pub playback: Option<PlaybackProp>,
+    /// Kept in the store instead of the buttons, and left empty in states
+    /// read from them unless they were sent before.
+    pub emoji: EmojiProp,
+    pub effects: Effects,
 }
 
 impl Callback {
     pub fn kind(&self) -> CallbackKind {
         match self {
-            Self::Small | Self::Medium | Self::Large => CallbackKind::Size,
-            Self::Left | Self::Center | Self::Right => CallbackKind::Position,
-            Self::SpeedUp => CallbackKind::Time,
+            Self::Small | Self::Medium | Self::Large | Self::CustomEmoji => CallbackKind::Size,
+            Self::Shorter | Self::Taller => CallbackKind::Size,
+            Self::Left | Self::Center | Self::Right | Self::Top | Self::Middle | Self::Bottom => {
+                CallbackKind::Position
+            }
+            Self::SpeedUp | Self::TrimStart | Self::TrimMiddle | Self::TrimEnd => {
+                CallbackKind::Time
+            }
+            Self::Emoji(_) => CallbackKind::Emoji,
+            Self::Setting(_) => CallbackKind::Setting,
+            Self::Export(_) => CallbackKind::Export,
+            Self::RemoveBackground
+            | Self::ToleranceDown
+            | Self::ToleranceUp
+            | Self::ToggleOutline
+            | Self::NextOutlineColor
+            | Self::Thinner
+            | Self::Thicker
+            | Self::RemoveText
+            | Self::MoveText
+            | Self::NextTextStyle
+            | Self::NextCrop
+            | Self::NudgeLeft
+            | Self::NudgeRight
+            | Self::NudgeUp
+            | Self::NudgeDown
+            | Self::ZoomIn
+            | Self::ZoomOut => CallbackKind::Effect,
         }
     }
 }
@@ -66,11 +379,51 @@ impl FromStr for Callback {
             "Small" => Ok(Self::Small),
             "Medium" => Ok(Self::Medium),
             "Large" => Ok(Self::Large),
+            "Custom" => Ok(Self::CustomEmoji),
             "Left" => Ok(Self::Left),
             "Center" => Ok(Self::Center),
             "Right" => Ok(Self::Right),
+            "Top" => Ok(Self::Top),
+            "Mid" => Ok(Self::Middle),
+            "Bottom" => Ok(Self::Bottom),
+            "Shorter" => Ok(Self::Shorter),
+            "Taller" => Ok(Self::Taller),
             "SpeedUp" => Ok(Self::SpeedUp),
-            _ => Err(CallbackError::Unknown(s.to_owned())),
+            "Start" => Ok(Self::TrimStart),
+            "Middle" => Ok(Self::TrimMiddle),
+            "End" => Ok(Self::TrimEnd),
+            "Bg" => Ok(Self::RemoveBackground),
+            "Bg-" => Ok(Self::ToleranceDown),
+            "Bg+" => Ok(Self::ToleranceUp),
+            "Line" => Ok(Self::ToggleOutline),
+            "LineCol" => Ok(Self::NextOutlineColor),
+            "Line-" => Ok(Self::Thinner),
+            "Line+" => Ok(Self::Thicker),
+            "Text" => Ok(Self::RemoveText),
+            "TextPos" => Ok(Self::MoveText),
+            "TextSty" => Ok(Self::NextTextStyle),
+            "Crop" => Ok(Self::NextCrop),
+            "CropL" => Ok(Self::NudgeLeft),
+            "CropR" => Ok(Self::NudgeRight),
+            "CropU" => Ok(Self::NudgeUp),
+            "CropD" => Ok(Self::NudgeDown),
+            "Zoom+" => Ok(Self::ZoomIn),
+            "Zoom-" => Ok(Self::ZoomOut),
+            _ => match (s.strip_prefix("Set"), s.strip_prefix("To")) {
+                (Some(setting), _) => setting
+                    .parse()
+                    .map(Self::Setting)
+                    .map_err(|_| CallbackError::Unknown(s.to_owned())),
+                (_, Some(format)) => format
+                    .parse()
+                    .map(Self::Export)
+                    .map_err(|_| CallbackError::Unknown(s.to_owned())),
+                (None, None) => SUGGESTED_EMOJI
+                    .iter()
+                    .find(|&&e| e == s)
+                    .map(|&e| Self::Emoji(e))
+                    .ok_or_else(|| CallbackError::Unknown(s.to_owned())),
+            },
         }
     }
 }
@@ -84,28 +437,173 @@ impl From<Alignment> for Callback {
         }
     }
 }
-impl From<Callback> for InlineKeyboardButton {
-    fn from(callback: Callback) -> Self {
+impl From<VerticalAlignment> for Callback {
+    fn from(position: VerticalAlignment) -> Self {
+        use VerticalAlignment::*;
+        match position {
+            Top => Self::Top,
+            Middle => Self::Middle,
+            Bottom => Self::Bottom,
+        }
+    }
+}
+impl Display for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Callback::*;
+        let data = match self {
+            Small => "Small",
+            Medium => "Medium",
+            Large => "Large",
+            CustomEmoji => "Custom",
+            Left => "Left",
+            Center => "Center",
+            Right => "Right",
+            Top => "Top",
+            Middle => "Mid",
+            Bottom => "Bottom",
+            Shorter => "Shorter",
+            Taller => "Taller",
+            SpeedUp => "SpeedUp",
+            TrimStart => "Start",
+            TrimMiddle => "Middle",
+            TrimEnd => "End",
+            RemoveBackground => "Bg",
+            ToleranceDown => "Bg-",
+            ToleranceUp => "Bg+",
+            ToggleOutline => "Line",
+            NextOutlineColor => "LineCol",
+            Thinner => "Line-",
+            Thicker => "Line+",
+            RemoveText => "Text",
+            MoveText => "TextPos",
+            NextTextStyle => "TextSty",
+            NextCrop => "Crop",
+            NudgeLeft => "CropL",
+            NudgeRight => "CropR",
+            NudgeUp => "CropU",
+            NudgeDown => "CropD",
+            ZoomIn => "Zoom+",
+            ZoomOut => "Zoom-",
+            Emoji(e) => e,
+            Setting(setting) => return write!(f, "Set{}", setting),
+            Export(format) => return write!(f, "To{}", format),
+        };
+        write!(f, "{}", data)
+    }
+}
+
+impl From<CallbackData> for InlineKeyboardButton {
+    fn from(data: CallbackData) -> Self {
         use Callback::*;
-        let (text, data) = match callback {
-            Small => ("Small", "Small"),
-            Medium => ("Medium", "Medium"),
-            Large => ("Large", "Large"),
-            Left => ("Left", "Left"),
-            Center => ("Center", "Center"),
-            Right => ("Right", "Right"),
-            SpeedUp => ("Speed me up!", "SpeedUp"),
+        let text = match data.callback {
+            SpeedUp => "Speed me up!".to_owned(),
+            TrimStart => "Trim: start".to_owned(),
+            TrimMiddle => "Trim: middle".to_owned(),
+            TrimEnd => "Trim: end".to_owned(),
+            CustomEmoji => "Emoji".to_owned(),
+            Middle => "Middle".to_owned(),
+            Shorter => "Height −".to_owned(),
+            Taller => "Height +".to_owned(),
+            RemoveBackground => match data.state.as_ref().and_then(|s| s.effects.key) {
+                Some(_) => "Keep background".to_owned(),
+                None => "Remove background".to_owned(),
+            },
+            ToleranceDown => "Tolerance −".to_owned(),
+            ToleranceUp => "Tolerance +".to_owned(),
+            ToggleOutline => match data.state.as_ref().and_then(|s| s.effects.outline) {
+                Some(_) => "Remove outline".to_owned(),
+                None => "Add outline".to_owned(),
+            },
+            NextOutlineColor => match data.state.as_ref().and_then(|s| s.effects.outline) {
+                Some(outline) => format!("Colour: {}", outline.color.text()),
+                None => "Colour".to_owned(),
+            },
+            RemoveText => "Remove text".to_owned(),
+            MoveText => match data.state.as_ref().and_then(|s| s.effects.text.as_ref()) {
+                Some(text) if text.position == TextPosition::Top => "Move text down".to_owned(),
+                _ => "Move text up".to_owned(),
+            },
+            NextTextStyle => match data.state.as_ref().and_then(|s| s.effects.text.as_ref()) {
+                Some(text) => format!("Style: {}", text.style.text()),
+                None => "Style".to_owned(),
+            },
+            NextCrop => match data.state.as_ref().map(|s| s.effects.crop) {
+                Some(crop) => format!("Crop: {}", crop.text()),
+                None => "Crop".to_owned(),
+            },
+            NudgeLeft => "←".to_owned(),
+            NudgeRight => "→".to_owned(),
+            NudgeUp => "↑".to_owned(),
+            NudgeDown => "↓".to_owned(),
+            ZoomIn => "Zoom +".to_owned(),
+            ZoomOut => "Zoom −".to_owned(),
+            Setting(setting) => setting.text().to_owned(),
+            Export(format) => format!("Export as {}", format.text()),
+            callback => callback.to_string(),
         };
-        Self::callback(text.to_owned(), data.to_owned())
+        Self::callback(text, data.to_string())
+    }
+}
+impl From<Callback> for CallbackData {
+    fn from(callback: Callback) -> Self {
+        Self {
+            callback,
+            state: None,
+        }
+    }
+}
+impl Display for CallbackData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.state {
+            Some(state) => write!(f, "{}:{}", self.callback, state),
+            None => write!(f, "{}", self.callback),
+        }
+    }
+}
+impl FromStr for CallbackData {
+    type Err = CallbackError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((callback, state)) => Ok(Self {
+                callback: callback.parse()?,
+                state: Some(state.parse()?),
+            }),
+            None => Ok(s.parse::<Callback>()?.into()),
+        }
+    }
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Image => "static",
+                Self::Video => "video",
+                Self::Animated => "animated",
+            }
+        )
+    }
+}
+impl FromStr for MediaType {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "static" => Ok(Self::Image),
+            "video" => Ok(Self::Video),
+            "animated" => Ok(Self::Animated),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
     }
 }
 
 impl Alignment {
-    fn pad_x(&self, width: u32) -> u32 {
+    fn anchor(&self) -> Anchor {
         match self {
-            Self::Left => 0,
-            Self::Center => (512 - width) / 2,
-            Self::Right => 512 - width,
+            Self::Left => Anchor::Start,
+            Self::Center => Anchor::Middle,
+            Self::Right => Anchor::End,
         }
     }
 }
@@ -145,46 +643,128 @@ impl TryFrom<Callback> for Alignment {
         }
     }
 }
+impl VerticalAlignment {
+    fn anchor(&self) -> Anchor {
+        match self {
+            Self::Top => Anchor::Start,
+            Self::Middle => Anchor::Middle,
+            Self::Bottom => Anchor::End,
+        }
+    }
+}
+impl Display for VerticalAlignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Top => "Top",
+                Self::Middle => "Middle",
+                Self::Bottom => "Bottom",
+            }
+        )
+    }
+}
+impl FromStr for VerticalAlignment {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Top" => Ok(Self::Top),
+            "Middle" => Ok(Self::Middle),
+            "Bottom" => Ok(Self::Bottom),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+impl TryFrom<Callback> for VerticalAlignment {
+    type Error = CallbackError;
+    fn try_from(callback: Callback) -> Result<Self, Self::Error> {
+        use Callback::*;
+        match callback {
+            Top => Ok(Self::Top),
+            Middle => Ok(Self::Middle),
+            Bottom => Ok(Self::Bottom),
+            _ => Err(CallbackError::Incompatible),
+        }
+    }
+}
+/// Width and height of a custom emoji in pixels.
+pub const EMOJI_SIZE: u32 = 100;
 impl LayoutProp {
-    pub fn resize(&self, width: u32, height: u32) -> (u32, u32, Option<u32>) {
-        let b_width = 512;
-        let b_height = match self {
-            Self::Small(_) => 128,
-            Self::Medium(_) => 256,
-            Self::Large => 512,
-        };
-        let n_width = ((width * b_height) as f32 / height as f32) as u32;
-        let n_height = ((height * b_width) as f32 / width as f32) as u32;
-
-        let (width, height) = if n_width <= b_width {
-            (n_width, b_height)
-        } else {
-            (b_width, n_height)
+    /// Returns where media of the given size goes in the sticker, once
+    /// cropped.
+    pub fn resize(&self, width: u32, height: u32, crop: Crop) -> Placement {
+        geometry::place(width, height, self.frame(), crop)
+    }
+    fn frame(&self) -> Frame {
+        let (width, height) = match *self {
+            Self::Small(..) => (STICKER_SIDE, 128),
+            Self::Medium(..) => (STICKER_SIDE, 256),
+            Self::Height(height, ..) => (STICKER_SIDE, height),
+            Self::Large => (STICKER_SIDE, STICKER_SIDE),
+            Self::CustomEmoji => (EMOJI_SIZE, EMOJI_SIZE),
         };
-        use Ordering::*;
-        let x = match (width.cmp(&512), height.cmp(&512), self) {
-            (Less, Less, Self::Small(p) | Self::Medium(p)) => Some(p.pad_x(width)),
-            _ => None,
+        let pad = match self {
+            Self::Small(p, v) | Self::Medium(p, v) | Self::Height(_, p, v) => {
+                Some((p.anchor(), v.anchor()))
+            }
+            Self::Large => None,
+            Self::CustomEmoji => Some((Anchor::Middle, Anchor::Middle)),
         };
-        (b_width, b_height, x)
+        Frame { width, height, pad }
+    }
+    /// Returns the offset of media of the given size within the box, if the
+    /// box should be padded around it.
+    pub fn offset(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        geometry::offset(self.frame(), width, height)
+    }
+    /// A box of the given height, named if it is one of the usual ones.
+    fn with_height(height: u32, p: Alignment, v: VerticalAlignment) -> Self {
+        match height {
+            128 => Self::Small(p, v),
+            256 => Self::Medium(p, v),
+            height => Self::Height(height, p, v),
+        }
+    }
+    fn alignments(&self) -> Option<(Alignment, VerticalAlignment)> {
+        match *self {
+            Self::Small(p, v) | Self::Medium(p, v) | Self::Height(_, p, v) => Some((p, v)),
+            Self::Large | Self::CustomEmoji => None,
+        }
     }
     pub fn reset_size(self, s: Callback) -> Result<Self, CallbackError> {
         use Callback::*;
+        let (p, v) = self.alignments().unwrap_or_default();
         match (self, s) {
-            (Self::Small(p), Medium) => Ok(Self::Medium(p)),
-            (Self::Medium(p), Small) => Ok(Self::Small(p)),
-            (Self::Small(_) | Self::Medium(_), Large) => Ok(Self::Large),
-            (Self::Large, Small) => Ok(Self::Small(Alignment::Center)),
-            (Self::Large, Medium) => Ok(Self::Medium(Alignment::Center)),
+            (Self::Small(..), Small) | (Self::Medium(..), Medium) => {
+                Err(CallbackError::Incompatible)
+            }
+            (_, Small) => Ok(Self::Small(p, v)),
+            (_, Medium) => Ok(Self::Medium(p, v)),
+            (Self::Large, Large) | (Self::CustomEmoji, CustomEmoji) => {
+                Err(CallbackError::Incompatible)
+            }
+            (_, Large) => Ok(Self::Large),
+            (_, CustomEmoji) => Ok(Self::CustomEmoji),
+            (Self::Large | Self::CustomEmoji, _) => Err(CallbackError::Incompatible),
+            (_, Shorter | Taller) => {
+                let height = self.frame().height;
+                let height = match s {
+                    Shorter => height.saturating_sub(HEIGHT_STEP).max(HEIGHT_STEP),
+                    _ => (height + HEIGHT_STEP).min(512),
+                };
+                Ok(Self::with_height(height, p, v))
+            }
             _ => Err(CallbackError::Incompatible),
         }
     }
     pub fn reset_alignment(self, s: Callback) -> Result<Self, CallbackError> {
-        match self {
-            Self::Small(_) => Ok(Self::Small(s.try_into()?)),
-            Self::Medium(_) => Ok(Self::Medium(s.try_into()?)),
-            Self::Large => Err(CallbackError::Incompatible),
-        }
+        let (p, v) = self.alignments().ok_or(CallbackError::Incompatible)?;
+        let (p, v) = match s {
+            Callback::Top | Callback::Middle | Callback::Bottom => (p, s.try_into()?),
+            _ => (s.try_into()?, v),
+        };
+        Ok(Self::with_height(self.frame().height, p, v))
     }
 }
 impl Display for LayoutProp {
@@ -193,9 +773,11 @@ impl Display for LayoutProp {
             f,
             "{}",
             match self {
-                Self::Small(p) => format!("Small,{}", p),
-                Self::Medium(p) => format!("Medium,{}", p),
-                Self::Large => format!("Large,/"),
+                Self::Small(p, v) => format!("Small,{},{}", p, v),
+                Self::Medium(p, v) => format!("Medium,{},{}", p, v),
+                Self::Height(height, p, v) => format!("Height{},{},{}", height, p, v),
+                Self::Large => "Large,/".to_owned(),
+                Self::CustomEmoji => "CustomEmoji,/".to_owned(),
             }
         )
     }
@@ -203,15 +785,25 @@ impl Display for LayoutProp {
 impl FromStr for LayoutProp {
     type Err = PropsError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
         let arr: Vec<_> = s.split(',').collect();
-        if arr.len() != 2 {
-            return Err(PropsError::Parse(s.to_owned()));
+        if !(2..=3).contains(&arr.len()) {
+            return Err(err());
         }
-        match arr[0] {
-            "Small" => Ok(Self::Small(arr[1].parse()?)),
-            "Medium" => Ok(Self::Medium(arr[1].parse()?)),
-            "Large" => Ok(Self::Large),
-            _ => Err(PropsError::Parse(s.to_owned())),
+        // Layouts from before vertical alignment are in the middle.
+        let v = arr.get(2).map(|v| v.parse()).transpose()?;
+        match (arr[0], v) {
+            ("Small", v) => Ok(Self::Small(arr[1].parse()?, v.unwrap_or_default())),
+            ("Medium", v) => Ok(Self::Medium(arr[1].parse()?, v.unwrap_or_default())),
+            ("Large", None) => Ok(Self::Large),
+            ("CustomEmoji", None) => Ok(Self::CustomEmoji),
+            (kind, Some(v)) => match kind.strip_prefix("Height").map(str::parse) {
+                Some(Ok(height)) if is_free_height(height) => {
+                    Ok(Self::Height(height, arr[1].parse()?, v))
+                }
+                _ => Err(err()),
+            },
+            _ => Err(err()),
         }
     }
 }
@@ -220,34 +812,1053 @@ impl From<(u32, u32)> for LayoutProp {
         if width > 384 || height > 256 {
             Self::Large
         } else if height > 128 {
-            Self::Medium(Alignment::Center)
+            Self::Medium(Alignment::Center, VerticalAlignment::Middle)
         } else {
-            Self::Small(Alignment::Center)
+            Self::Small(Alignment::Center, VerticalAlignment::Middle)
+        }
+    }
+}
+fn is_free_height(height: u32) -> bool {
+    (HEIGHT_STEP..=512).contains(&height) && height.is_multiple_of(HEIGHT_STEP)
+}
+impl PlaybackProp {
+    pub const ORIGINAL: Self = Self {
+        speed_up: false,
+        trim: None,
+    };
+    pub const SPEED_UP: Self = Self {
+        speed_up: true,
+        trim: None,
+    };
+    pub fn trim(trim: Trim) -> Self {
+        Self {
+            speed_up: false,
+            trim: Some(trim),
+        }
+    }
+}
+impl TryFrom<Callback> for PlaybackProp {
+    type Error = CallbackError;
+    fn try_from(callback: Callback) -> Result<Self, Self::Error> {
+        use Callback::*;
+        match callback {
+            SpeedUp => Ok(Self::SPEED_UP),
+            TrimStart => Ok(Self::trim(Trim::Start)),
+            TrimMiddle => Ok(Self::trim(Trim::Middle)),
+            TrimEnd => Ok(Self::trim(Trim::End)),
+            _ => Err(CallbackError::Incompatible),
         }
     }
 }
 impl FromStr for PlaybackProp {
     type Err = PropsError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self {
-            speed_up: match s {
-                "speed_up" => true,
-                "original_speed" => false,
-                _ => Err(PropsError::Parse(s.to_owned()))?,
+        match s {
+            "speed_up" => Ok(Self::SPEED_UP),
+            "original_speed" => Ok(Self::ORIGINAL),
+            _ => match s.strip_prefix("trim_") {
+                Some(trim) => Ok(Self::trim(trim.parse()?)),
+                None => Err(PropsError::Parse(s.to_owned())),
             },
-        })
+        }
     }
 }
 impl Display for PlaybackProp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.trim {
+            Some(trim) => write!(f, "trim_{}", trim),
+            None if self.speed_up => write!(f, "speed_up"),
+            None => write!(f, "original_speed"),
+        }
+    }
+}
+
+impl Trim {
+    /// Offset of the window in a clip of `duration` seconds.
+    pub fn offset(&self, duration: f32) -> f32 {
+        let last = (duration - MAX_DURATION).max(0.0);
+        match self {
+            Self::Start => 0.0,
+            Self::Middle => last / 2.0,
+            Self::End => last,
+            Self::At(t) => t.min(last),
+        }
+    }
+}
+impl Display for Trim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Start => write!(f, "start"),
+            Self::Middle => write!(f, "middle"),
+            Self::End => write!(f, "end"),
+            Self::At(t) => write!(f, "{:.1}", t),
+        }
+    }
+}
+impl FromStr for Trim {
+    type Err = PropsError;
+    /// Accepts the named positions as well as timestamps like `12.5`, `1:02`
+    /// or `0:01:02.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        match s.trim() {
+            "start" => Ok(Self::Start),
+            "middle" => Ok(Self::Middle),
+            "end" => Ok(Self::End),
+            t => {
+                let mut seconds = 0.0;
+                for part in t.split(':') {
+                    let x: f32 = part.parse().map_err(|_| err())?;
+                    if !x.is_finite() || x < 0.0 {
+                        return Err(err());
+                    }
+                    seconds = seconds * 60.0 + x;
+                }
+                if seconds >= 10000.0 {
+                    return Err(err());
+                }
+                Ok(Self::At((seconds * 10.0).round() / 10.0))
+            }
+        }
+    }
+}
+
+impl EmojiProp {
+    pub fn toggle(mut self, emoji: &str) -> Result<Self, PropsError> {
+        match self.emojis.iter().position(|e| e == emoji) {
+            Some(i) => {
+                self.emojis.remove(i);
+                Ok(self)
+            }
+            None => {
+                self.emojis.push(emoji.to_owned());
+                self.checked()
+            }
+        }
+    }
+    /// Fails if there are more emoji than Telegram takes for one sticker.
+    pub fn checked(self) -> Result<Self, PropsError> {
+        if self.emojis.len() > MAX_EMOJI {
+            return Err(PropsError::EmojiLength);
+        }
+        Ok(self)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.emojis.is_empty()
+    }
+}
+/// Whether the code point shows as an emoji by itself.
+fn is_emoji_base(c: char) -> bool {
+    matches!(c,
+        '\u{a9}' | '\u{ae}' | '\u{203c}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{21aa}' | '\u{231a}'..='\u{23ff}' | '\u{24c2}'
+        | '\u{25aa}'..='\u{25fe}' | '\u{2600}'..='\u{27bf}' | '\u{2934}' | '\u{2935}'
+        | '\u{2b05}'..='\u{2b55}' | '\u{3030}' | '\u{303d}' | '\u{3297}' | '\u{3299}'
+        | '\u{1f000}'..='\u{1faff}')
+}
+
+/// Whether the text is made of emoji only, including keycaps like 1️⃣, flags
+/// and sequences joined with skin tones, variation selectors or ZWJ.
+fn is_emoji(s: &str) -> bool {
+    let chars: Vec<char> = s.chars().collect();
+    let mut base = false;
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            // Digits, `#` and `*` are emoji only as the base of a keycap.
+            '0'..='9' | '#' | '*' => {
+                if !matches!(
+                    chars[i + 1..],
+                    ['\u{20e3}', ..] | ['\u{fe0f}', '\u{20e3}', ..]
+                ) {
+                    return false;
+                }
+                base = true;
+            }
+            // ZWJ, variation selectors, keycap, skin tones and flag tags.
+            '\u{200d}'
+            | '\u{fe0e}'
+            | '\u{fe0f}'
+            | '\u{20e3}'
+            | '\u{1f3fb}'..='\u{1f3ff}'
+            | '\u{e0020}'..='\u{e007f}' => {}
+            c if is_emoji_base(c) => base = true,
+            _ => return false,
+        }
+    }
+    base
+}
+
+impl FromStr for EmojiProp {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let emojis: Vec<_> = s.split_whitespace().map(str::to_owned).collect();
+        if !emojis.iter().all(|e| is_emoji(e)) {
+            Err(PropsError::Emoji(s.to_owned()))
+        } else {
+            Ok(Self { emojis })
+        }
+    }
+}
+impl Display for EmojiProp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.emojis.join(" "))
+    }
+}
+
+impl ChromaKey {
+    pub const AUTO: Self = Self {
+        color: None,
+        tolerance: DEFAULT_TOLERANCE,
+    };
+}
+impl Display for ChromaKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.color {
+            Some([r, g, b]) => write!(f, "{:02x}{:02x}{:02x}{}", r, g, b, self.tolerance),
+            None => write!(f, "a{}", self.tolerance),
+        }
+    }
+}
+impl FromStr for ChromaKey {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        let (color, tolerance) = match s.strip_prefix('a') {
+            Some(tolerance) => (None, tolerance),
+            None if s.is_char_boundary(6) => {
+                let (color, tolerance) = s.split_at(6);
+                (Some(parse_hex(color)?), tolerance)
+            }
+            None => return Err(err()),
+        };
+        match tolerance.parse() {
+            Ok(tolerance) if tolerance <= 100 => Ok(Self { color, tolerance }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Parses a colour written as hex after a `#`, like `#00ff00`. The `#`
+/// keeps words such as "decade" from being taken as colours.
+pub fn parse_color(s: &str) -> Result<[u8; 3], PropsError> {
+    match s.trim().strip_prefix('#') {
+        Some(hex) => parse_hex(hex).map_err(|_| PropsError::Color(s.to_owned())),
+        None => Err(PropsError::Color(s.to_owned())),
+    }
+}
+
+/// Parses a colour written as bare hex, like `00FF00`.
+fn parse_hex(hex: &str) -> Result<[u8; 3], PropsError> {
+    let err = || PropsError::Color(hex.to_owned());
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(err());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| err());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl OutlineColor {
+    pub const ALL: [Self; 4] = [Self::White, Self::Black, Self::Yellow, Self::Blue];
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::White => "White",
+            Self::Black => "Black",
+            Self::Yellow => "Yellow",
+            Self::Blue => "Blue",
+        }
+    }
+    pub fn rgb(&self) -> [u8; 3] {
+        match self {
+            Self::White => [0xff, 0xff, 0xff],
+            Self::Black => [0x00, 0x00, 0x00],
+            Self::Yellow => [0xff, 0xd6, 0x00],
+            Self::Blue => [0x29, 0x79, 0xff],
+        }
+    }
+    fn next(self) -> Self {
+        let i = Self::ALL
+            .iter()
+            .position(|&c| c == self)
+            .unwrap_or_default();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+impl Display for OutlineColor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
             "{}",
-            if self.speed_up {
-                "speed_up"
+            match self {
+                Self::White => "w",
+                Self::Black => "k",
+                Self::Yellow => "y",
+                Self::Blue => "b",
+            }
+        )
+    }
+}
+impl FromStr for OutlineColor {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "w" => Ok(Self::White),
+            "k" => Ok(Self::Black),
+            "y" => Ok(Self::Yellow),
+            "b" => Ok(Self::Blue),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+
+impl Outline {
+    pub const DEFAULT: Self = Self {
+        color: OutlineColor::White,
+        width: 8,
+    };
+}
+impl Display for Outline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.color, self.width)
+    }
+}
+impl FromStr for Outline {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        if !s.is_char_boundary(1) {
+            return Err(err());
+        }
+        let (color, width) = s.split_at(1);
+        match width.parse() {
+            Ok(width) if (MIN_OUTLINE_WIDTH..=MAX_OUTLINE_WIDTH).contains(&width) => Ok(Self {
+                color: color.parse()?,
+                width,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl TextPosition {
+    fn flip(self) -> Self {
+        match self {
+            Self::Top => Self::Bottom,
+            Self::Bottom => Self::Top,
+        }
+    }
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Top => "top",
+            Self::Bottom => "bottom",
+        }
+    }
+}
+
+impl TextStyle {
+    pub const ALL: [Self; 3] = [Self::Meme, Self::Subtitle, Self::Dark];
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Meme => "Meme",
+            Self::Subtitle => "Subtitle",
+            Self::Dark => "Dark",
+        }
+    }
+    /// Colours of the letters and of their border.
+    pub fn colors(&self) -> ([u8; 3], [u8; 3]) {
+        const WHITE: [u8; 3] = [0xff, 0xff, 0xff];
+        const BLACK: [u8; 3] = [0x00, 0x00, 0x00];
+        match self {
+            Self::Meme => (WHITE, BLACK),
+            Self::Subtitle => ([0xff, 0xd6, 0x00], BLACK),
+            Self::Dark => (BLACK, WHITE),
+        }
+    }
+    fn next(self) -> Self {
+        let i = Self::ALL
+            .iter()
+            .position(|&s| s == self)
+            .unwrap_or_default();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+
+impl Display for TextOverlay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let position = match self.position {
+            TextPosition::Top => 't',
+            TextPosition::Bottom => 'b',
+        };
+        let style = match self.style {
+            TextStyle::Meme => 'm',
+            TextStyle::Subtitle => 's',
+            TextStyle::Dark => 'd',
+        };
+        write!(f, "{}{}", position, style)
+    }
+}
+impl FromStr for TextOverlay {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        let mut chars = s.chars();
+        let position = match chars.next() {
+            Some('t') => TextPosition::Top,
+            Some('b') => TextPosition::Bottom,
+            _ => return Err(err()),
+        };
+        let style = match chars.next() {
+            Some('m') => TextStyle::Meme,
+            Some('s') => TextStyle::Subtitle,
+            Some('d') => TextStyle::Dark,
+            _ => return Err(err()),
+        };
+        match chars.next() {
+            None => Ok(Self {
+                text: String::new(),
+                position,
+                style,
+            }),
+            Some(_) => Err(err()),
+        }
+    }
+}
+
+impl Crop {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Fit => "Fit",
+            Self::Fill => "Fill",
+            Self::Square => "Square",
+            Self::Manual { .. } => "Manual",
+        }
+    }
+    /// How many times the media is magnified.
+    pub fn zoom(&self) -> f32 {
+        match self {
+            Self::Manual { zoom, .. } => 1.0 + f32::from(*zoom) / 2.0,
+            _ => 1.0,
+        }
+    }
+    fn next(self) -> Self {
+        match self {
+            Self::Fit => Self::Fill,
+            Self::Fill => Self::Square,
+            Self::Square => Self::Manual {
+                zoom: 1,
+                x: 0,
+                y: 0,
+            },
+            Self::Manual { .. } => Self::Fit,
+        }
+    }
+    fn nudge(self, callback: Callback) -> Result<Self, CallbackError> {
+        use Callback::*;
+        let (zoom, x, y) = match self {
+            Self::Manual { zoom, x, y } => (zoom, x, y),
+            _ => return Err(CallbackError::Incompatible),
+        };
+        let (zoom, x, y) = match callback {
+            NudgeLeft => (zoom, (x - 1).max(-MAX_NUDGE), y),
+            NudgeRight => (zoom, (x + 1).min(MAX_NUDGE), y),
+            NudgeUp => (zoom, x, (y - 1).max(-MAX_NUDGE)),
+            NudgeDown => (zoom, x, (y + 1).min(MAX_NUDGE)),
+            ZoomIn => ((zoom + 1).min(MAX_ZOOM), x, y),
+            ZoomOut => (zoom.saturating_sub(1), x, y),
+            _ => return Err(CallbackError::Incompatible),
+        };
+        Ok(Self::Manual { zoom, x, y })
+    }
+}
+impl Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fit => write!(f, "t"),
+            Self::Fill => write!(f, "f"),
+            Self::Square => write!(f, "s"),
+            // Nudges are shifted to single digits from 0 to 2 * MAX_NUDGE.
+            Self::Manual { zoom, x, y } => {
+                write!(f, "m{}{:x}{:x}", zoom, x + MAX_NUDGE, y + MAX_NUDGE)
+            }
+        }
+    }
+}
+impl FromStr for Crop {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        match s {
+            "t" => Ok(Self::Fit),
+            "f" => Ok(Self::Fill),
+            "s" => Ok(Self::Square),
+            _ => {
+                let digits = s.strip_prefix('m').ok_or_else(err)?;
+                let digits: Vec<u32> = digits.chars().filter_map(|c| c.to_digit(16)).collect();
+                let nudge = |d: u32| {
+                    let d = d as i8 - MAX_NUDGE;
+                    (-MAX_NUDGE..=MAX_NUDGE).contains(&d).then_some(d)
+                };
+                match digits[..] {
+                    [zoom, x, y] if s.len() == 4 && zoom <= u32::from(MAX_ZOOM) => {
+                        Ok(Self::Manual {
+                            zoom: zoom as u8,
+                            x: nudge(x).ok_or_else(err)?,
+                            y: nudge(y).ok_or_else(err)?,
+                        })
+                    }
+                    _ => Err(err()),
+                }
+            }
+        }
+    }
+}
+
+impl Effects {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+    pub fn apply(self, callback: Callback) -> Result<Self, CallbackError> {
+        use Callback::*;
+        match (callback, self.key, self.outline) {
+            (RemoveBackground, Some(_), _) => Ok(Self { key: None, ..self }),
+            (RemoveBackground, None, _) => Ok(Self {
+                key: Some(ChromaKey::AUTO),
+                ..self
+            }),
+            (ToleranceDown, Some(key), _) => Ok(Self {
+                key: Some(ChromaKey {
+                    tolerance: key.tolerance.saturating_sub(TOLERANCE_STEP),
+                    ..key
+                }),
+                ..self
+            }),
+            (ToleranceUp, Some(key), _) => Ok(Self {
+                key: Some(ChromaKey {
+                    tolerance: (key.tolerance + TOLERANCE_STEP).min(100),
+                    ..key
+                }),
+                ..self
+            }),
+            (ToggleOutline, _, Some(_)) => Ok(Self {
+                outline: None,
+                ..self
+            }),
+            (ToggleOutline, _, None) => Ok(Self {
+                outline: Some(Outline::DEFAULT),
+                ..self
+            }),
+            (NextOutlineColor, _, Some(outline)) => Ok(Self {
+                outline: Some(Outline {
+                    color: outline.color.next(),
+                    ..outline
+                }),
+                ..self
+            }),
+            (Thinner, _, Some(outline)) => Ok(Self {
+                outline: Some(Outline {
+                    width: outline
+                        .width
+                        .saturating_sub(OUTLINE_WIDTH_STEP)
+                        .max(MIN_OUTLINE_WIDTH),
+                    ..outline
+                }),
+                ..self
+            }),
+            (Thicker, _, Some(outline)) => Ok(Self {
+                outline: Some(Outline {
+                    width: (outline.width + OUTLINE_WIDTH_STEP).min(MAX_OUTLINE_WIDTH),
+                    ..outline
+                }),
+                ..self
+            }),
+            (RemoveText | MoveText | NextTextStyle, _, _) => {
+                let text = self.text.ok_or(CallbackError::Incompatible)?;
+                let text = match callback {
+                    MoveText => Some(TextOverlay {
+                        position: text.position.flip(),
+                        ..text
+                    }),
+                    NextTextStyle => Some(TextOverlay {
+                        style: text.style.next(),
+                        ..text
+                    }),
+                    _ => None,
+                };
+                Ok(Self { text, ..self })
+            }
+            (NextCrop, _, _) => Ok(Self {
+                crop: self.crop.next(),
+                ..self
+            }),
+            (NudgeLeft | NudgeRight | NudgeUp | NudgeDown | ZoomIn | ZoomOut, _, _) => Ok(Self {
+                crop: self.crop.nudge(callback)?,
+                ..self
+            }),
+            _ => Err(CallbackError::Incompatible),
+        }
+    }
+}
+impl Display for Effects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let effects: Vec<_> = [
+            self.key.map(|key| format!("k{}", key)),
+            self.outline.map(|outline| format!("o{}", outline)),
+            self.text.as_ref().map(|text| format!("t{}", text)),
+            (self.crop != Crop::Fit).then(|| format!("c{}", self.crop)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        write!(f, "{}", effects.join(","))
+    }
+}
+impl FromStr for Effects {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut effects = Self::default();
+        for effect in s.split(',').filter(|x| !x.is_empty()) {
+            if let Some(key) = effect.strip_prefix('k') {
+                effects.key = Some(key.parse()?);
+            } else if let Some(outline) = effect.strip_prefix('o') {
+                effects.outline = Some(outline.parse()?);
+            } else if let Some(text) = effect.strip_prefix('t') {
+                effects.text = Some(text.parse()?);
+            } else if let Some(crop) = effect.strip_prefix('c') {
+                effects.crop = crop.parse()?;
             } else {
-                "original_speed"
+                return Err(PropsError::Parse(s.to_owned()));
+            }
+        }
+        Ok(effects)
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn alignment(p: &Alignment) -> char {
+            match p {
+                Alignment::Left => 'l',
+                Alignment::Center => 'c',
+                Alignment::Right => 'r',
+            }
+        }
+        fn vertical(v: &VerticalAlignment) -> char {
+            match v {
+                VerticalAlignment::Top => 't',
+                VerticalAlignment::Middle => 'm',
+                VerticalAlignment::Bottom => 'b',
+            }
+        }
+        // Free heights are kept to a single digit in steps from the smallest.
+        let layout = match &self.layout {
+            LayoutProp::Small(p, v) => format!("S{}{}", alignment(p), vertical(v)),
+            LayoutProp::Medium(p, v) => format!("M{}{}", alignment(p), vertical(v)),
+            LayoutProp::Height(height, p, v) => format!(
+                "H{:x}{}{}",
+                height / HEIGHT_STEP - 1,
+                alignment(p),
+                vertical(v)
+            ),
+            LayoutProp::Large => "L".to_owned(),
+            LayoutProp::CustomEmoji => "E".to_owned(),
+        };
+        let playback = match self.playback {
+            Some(PlaybackProp {
+                trim: Some(trim), ..
+            }) => match trim {
+                Trim::Start => "ts".to_owned(),
+                Trim::Middle => "tm".to_owned(),
+                Trim::End => "te".to_owned(),
+                Trim::At(_) => format!("t{}", trim),
+            },
+            Some(PlaybackProp { speed_up: true, .. }) => "s".to_owned(),
+            Some(PlaybackProp {
+                speed_up: false, ..
+            }) => "o".to_owned(),
+            None => "".to_owned(),
+        };
+        // Emoji are kept in the store, as callback data has no room for as
+        // many as a sticker may have. Their field stays for older buttons.
+        write!(
+            f,
+            "{};{};{};;{}",
+            STATE_VERSION, layout, playback, self.effects
+        )
+    }
+}
+impl FromStr for State {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PropsError::Parse(s.to_owned());
+        let arr: Vec<_> = s.split(';').collect();
+        match arr[0].parse::<u8>() {
+            Ok(v) if v <= STATE_VERSION => (),
+            _ => return Err(err()),
+        }
+
+        let mut layout = arr.get(1).ok_or_else(err)?.chars();
+        let kind = layout.next();
+        let height = match kind {
+            Some('H') => match layout.next().and_then(|c| c.to_digit(16)) {
+                Some(h) => Some((h + 1) * HEIGHT_STEP),
+                None => return Err(err()),
+            },
+            _ => None,
+        };
+        let alignment = match layout.next() {
+            Some('l') => Some(Alignment::Left),
+            Some('c') => Some(Alignment::Center),
+            Some('r') => Some(Alignment::Right),
+            None => None,
+            _ => return Err(err()),
+        };
+        // States from before vertical alignment leave it out.
+        let vertical = match layout.next() {
+            Some('t') => VerticalAlignment::Top,
+            Some('m') | None => VerticalAlignment::Middle,
+            Some('b') => VerticalAlignment::Bottom,
+            _ => return Err(err()),
+        };
+        if layout.next().is_some() {
+            return Err(err());
+        }
+        let layout = match (kind, height, alignment) {
+            (Some('S'), None, Some(p)) => LayoutProp::Small(p, vertical),
+            (Some('M'), None, Some(p)) => LayoutProp::Medium(p, vertical),
+            (Some('H'), Some(height), Some(p)) => LayoutProp::Height(height, p, vertical),
+            (Some('L'), None, None) => LayoutProp::Large,
+            (Some('E'), None, None) => LayoutProp::CustomEmoji,
+            _ => return Err(err()),
+        };
+        let playback = match arr.get(2).copied().unwrap_or_default() {
+            "s" => Some(PlaybackProp::SPEED_UP),
+            "o" => Some(PlaybackProp::ORIGINAL),
+            "ts" => Some(PlaybackProp::trim(Trim::Start)),
+            "tm" => Some(PlaybackProp::trim(Trim::Middle)),
+            "te" => Some(PlaybackProp::trim(Trim::End)),
+            "" => None,
+            p => match p.strip_prefix('t').map(str::parse) {
+                Some(Ok(trim @ Trim::At(_))) => Some(PlaybackProp::trim(trim)),
+                _ => return Err(err()),
+            },
+        };
+        // Only buttons sent before emoji moved into the store carry them.
+        let emoji = arr
+            .get(3)
+            .map(|s| s.replace(',', " ").parse())
+            .transpose()?
+            .unwrap_or_default();
+        let effects = arr
+            .get(4)
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Self {
+            layout,
+            playback,
+            emoji,
+            effects,
+        })
+    }
+}
+
+impl Settings {
+    pub fn layout(&self, width: u32, height: u32) -> LayoutProp {
+        let layout = match self.size {
+            SizePref::Auto => LayoutProp::from((width, height)),
+            SizePref::Small => LayoutProp::Small(Alignment::Center, VerticalAlignment::Middle),
+            SizePref::Medium => LayoutProp::Medium(Alignment::Center, VerticalAlignment::Middle),
+            SizePref::Large => LayoutProp::Large,
+        };
+        match layout {
+            LayoutProp::Small(_, v) => LayoutProp::Small(self.alignment, v),
+            LayoutProp::Medium(_, v) => LayoutProp::Medium(self.alignment, v),
+            layout => layout,
+        }
+    }
+    pub fn playback(&self, duration: f32) -> PlaybackProp {
+        if self.playback == PlaybackPolicy::SpeedUp && duration > MAX_DURATION {
+            PlaybackProp::SPEED_UP
+        } else {
+            PlaybackProp::ORIGINAL
+        }
+    }
+    pub fn apply(self, setting: Setting) -> Self {
+        match setting {
+            Setting::Size(size) => Self { size, ..self },
+            Setting::Alignment(alignment) => Self { alignment, ..self },
+            Setting::Playback(playback) => Self { playback, ..self },
+            Setting::Format(format) => Self { format, ..self },
+        }
+    }
+    pub fn contains(&self, setting: Setting) -> bool {
+        self.apply(setting) == *self
+    }
+}
+
+impl Setting {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Size(SizePref::Auto) => "Auto size",
+            Self::Size(SizePref::Small) => "Small",
+            Self::Size(SizePref::Medium) => "Medium",
+            Self::Size(SizePref::Large) => "Large",
+            Self::Alignment(Alignment::Left) => "Left",
+            Self::Alignment(Alignment::Center) => "Center",
+            Self::Alignment(Alignment::Right) => "Right",
+            Self::Playback(PlaybackPolicy::Ask) => "Ask before speed-up",
+            Self::Playback(PlaybackPolicy::SpeedUp) => "Always speed up",
+            Self::Format(OutputFormat::Png) => "PNG",
+            Self::Format(OutputFormat::WebP) => "WebP",
+        }
+    }
+}
+impl Display for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Size(x) => write!(f, "Size{}", x),
+            Self::Alignment(x) => write!(f, "Alignment{}", x),
+            Self::Playback(x) => write!(f, "Playback{}", x),
+            Self::Format(x) => write!(f, "Format{}", x),
+        }
+    }
+}
+impl FromStr for Setting {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(x) = s.strip_prefix("Size") {
+            Ok(Self::Size(x.parse()?))
+        } else if let Some(x) = s.strip_prefix("Alignment") {
+            Ok(Self::Alignment(x.parse()?))
+        } else if let Some(x) = s.strip_prefix("Playback") {
+            Ok(Self::Playback(x.parse()?))
+        } else if let Some(x) = s.strip_prefix("Format") {
+            Ok(Self::Format(x.parse()?))
+        } else {
+            Err(PropsError::Parse(s.to_owned()))
+        }
+    }
+}
+impl Display for SizePref {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Auto => "Auto",
+                Self::Small => "Small",
+                Self::Medium => "Medium",
+                Self::Large => "Large",
+            }
+        )
+    }
+}
+impl FromStr for SizePref {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Auto" => Ok(Self::Auto),
+            "Small" => Ok(Self::Small),
+            "Medium" => Ok(Self::Medium),
+            "Large" => Ok(Self::Large),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+impl Display for PlaybackPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Ask => "Ask",
+                Self::SpeedUp => "SpeedUp",
+            }
+        )
+    }
+}
+impl FromStr for PlaybackPolicy {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Ask" => Ok(Self::Ask),
+            "SpeedUp" => Ok(Self::SpeedUp),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+    pub fn file_name(&self) -> String {
+        format!("sticker.{}", self.extension())
+    }
+}
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Png => "Png",
+                Self::WebP => "WebP",
+            }
+        )
+    }
+}
+impl FromStr for OutputFormat {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Png" => Ok(Self::Png),
+            "WebP" => Ok(Self::WebP),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+impl ExportFormat {
+    pub const ALL: [Self; 4] = [Self::Png, Self::Gif, Self::Mp4, Self::Json];
+    /// The formats offered for a sticker of the given type, default first.
+    pub fn choices(media_type: MediaType) -> &'static [Self] {
+        match media_type {
+            MediaType::Image => &[Self::Png],
+            MediaType::Video => &[Self::Mp4, Self::Gif],
+            #[cfg(feature = "lottie")]
+            MediaType::Animated => &[Self::Json, Self::Gif, Self::Mp4, Self::Png],
+            #[cfg(not(feature = "lottie"))]
+            MediaType::Animated => &[Self::Json],
+        }
+    }
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Png => "PNG",
+            Self::Gif => "GIF",
+            Self::Mp4 => "MP4",
+            Self::Json => "JSON",
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Mp4 => "mp4",
+            Self::Json => "json",
+        }
+    }
+    pub fn file_name(&self) -> String {
+        format!("sticker.{}", self.extension())
+    }
+}
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Png => "Png",
+                Self::Gif => "Gif",
+                Self::Mp4 => "Mp4",
+                Self::Json => "Json",
             }
         )
     }
 }
+impl FromStr for ExportFormat {
+    type Err = PropsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Png" => Ok(Self::Png),
+            "Gif" => Ok(Self::Gif),
+            "Mp4" => Ok(Self::Mp4),
+            "Json" => Ok(Self::Json),
+            _ => Err(PropsError::Parse(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_accepts_sequences() {
+        for s in ["🙂", "1️⃣", "#️⃣", "*⃣", "❤️", "👍🏽", "👨‍👩‍👧", "🇺🇦", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "©️"]
+        {
+            assert!(s.parse::<EmojiProp>().is_ok(), "{}", s);
+        }
+        let emoji: EmojiProp = "🙂 1️⃣".parse().unwrap();
+        assert_eq!(emoji.emojis, ["🙂", "1️⃣"]);
+    }
+
+    #[test]
+    fn longest_callback_data_fits() {
+        let data = CallbackData {
+            callback: Callback::NextOutlineColor,
+            state: Some(State {
+                layout: LayoutProp::Height(512, Alignment::Center, VerticalAlignment::Middle),
+                playback: Some(PlaybackProp::trim(Trim::At(9999.9))),
+                emoji: EmojiProp::default(),
+                effects: Effects {
+                    key: Some(ChromaKey {
+                        color: Some([255, 0, 255]),
+                        tolerance: 100,
+                    }),
+                    outline: Some(Outline {
+                        color: OutlineColor::White,
+                        width: MAX_OUTLINE_WIDTH,
+                    }),
+                    text: Some(TextOverlay::default()),
+                    crop: Crop::Manual {
+                        zoom: MAX_ZOOM,
+                        x: MAX_NUDGE,
+                        y: MAX_NUDGE,
+                    },
+                },
+            }),
+        };
+        let encoded = data.to_string();
+        assert!(
+            encoded.len() <= 64,
+            "{} is {} bytes",
+            encoded,
+            encoded.len()
+        );
+        assert_eq!(encoded.parse::<CallbackData>().unwrap(), data);
+    }
+
+    #[test]
+    fn emoji_stay_out_of_callback_data() {
+        let state = State {
+            layout: LayoutProp::Large,
+            playback: None,
+            emoji: "👨‍👩‍👧 🙂".parse().unwrap(),
+            effects: Effects::default(),
+        };
+        assert_eq!(state.to_string(), "1;L;;;");
+    }
+
+    #[test]
+    fn emoji_of_older_buttons_parse() {
+        let state: State = "1;L;;😀,👍;".parse().unwrap();
+        assert_eq!(state.emoji.emojis, ["😀", "👍"]);
+    }
+
+    #[test]
+    fn emoji_are_limited_to_what_telegram_takes() {
+        let emoji = EmojiProp {
+            emojis: vec!["🙂".to_owned(); MAX_EMOJI],
+        };
+        assert!(emoji.clone().checked().is_ok());
+        assert!(emoji.toggle("😀").is_err());
+    }
+
+    #[test]
+    fn emoji_rejects_text() {
+        for s in ["Привет", "你好", "hello", "1", "#", "🙂a", "é", "\u{fe0f}"] {
+            assert!(s.parse::<EmojiProp>().is_err(), "{}", s);
+        }
+    }
+}